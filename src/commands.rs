@@ -1,14 +1,36 @@
+use std::sync::Arc;
+
+use indexmap::IndexMap;
+use poise::ChoiceParameter;
 use serenity::{
     all::{
-        CreateActionRow, CreateButton, CreateChannel, CreateMessage, GuildChannel, GuildId,
-        PermissionOverwrite, Permissions, ReactionType, User,
+        CreateAllowedMentions, CreateAttachment, CreateButton, CreateChannel, CreateEmbed,
+        CreateMessage, EditMessage, Embed, GuildChannel, GuildId, Message, PermissionOverwrite,
+        PermissionOverwriteType, Permissions, ReactionType, Role, User,
     },
     async_trait,
+    futures::StreamExt,
 };
 use songbird::events::{Event, EventContext, EventHandler as VoiceEventHandler};
 use songbird::{TrackEvent, input::YoutubeDl};
 
-use crate::{Context, Error, HttpKey, MOD_MAIL_CONFIG, ROLE_CONFIG, handler::delete_all_messages};
+use crate::{
+    Context, Error, HttpKey, MOD_MAIL_CONFIG, REACTION_ROLES, ROLE_CONFIG, ROLE_HISTORY,
+    ROLE_MENUS, ROLE_SNAPSHOTS, SNIPPETS, TICKETS, TRANSLATE_OPTOUTS, TranslatorKey,
+    handler::{chunk_lines, delete_all_messages, within_sample_limit, within_translate_limits},
+    roles::{
+        ReactionRole, check_bindable, count_roles, emoji_key, render_role_stats,
+        role_menu_components,
+    },
+    tickets::{
+        TicketAction, TicketCategory, TicketStats, format_duration, notify_staff,
+        participant_overwrite, render_snippet, transcript_path,
+    },
+    translate::{
+        TranslatedMessage, Translator, language_name, locale_language, matching_languages, protect,
+        render_language_stats, split_message, translate_message, translate_text,
+    },
+};
 
 struct TrackErrorNotifier;
 
@@ -100,6 +122,7 @@ pub async fn play_yt(
 pub async fn modmail(
     ctx: Context<'_>,
     #[description = "Title"] message: Option<String>,
+    #[description = "What the ticket is about, defaults to Other"] category: Option<TicketCategory>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     match message {
@@ -108,24 +131,12 @@ pub async fn modmail(
                 PermissionOverwrite {
                     allow: Permissions::empty(),
                     deny: Permissions::all(),
-                    kind: serenity::all::PermissionOverwriteType::Role(GuildId::everyone_role(
+                    kind: PermissionOverwriteType::Role(GuildId::everyone_role(
                         &ctx.guild_id().unwrap(),
                     )),
                 },
-                PermissionOverwrite {
-                    allow: Permissions::VIEW_CHANNEL
-                        | Permissions::SEND_MESSAGES
-                        | Permissions::READ_MESSAGE_HISTORY,
-                    deny: Permissions::CREATE_PUBLIC_THREADS | Permissions::CREATE_PRIVATE_THREADS,
-                    kind: serenity::all::PermissionOverwriteType::Role(MOD_MAIL_CONFIG.mod_role),
-                },
-                PermissionOverwrite {
-                    allow: Permissions::VIEW_CHANNEL
-                        | Permissions::SEND_MESSAGES
-                        | Permissions::READ_MESSAGE_HISTORY,
-                    deny: Permissions::CREATE_PUBLIC_THREADS | Permissions::CREATE_PRIVATE_THREADS,
-                    kind: serenity::all::PermissionOverwriteType::Member(ctx.author().id),
-                },
+                participant_overwrite(PermissionOverwriteType::Role(MOD_MAIL_CONFIG.mod_role)),
+                participant_overwrite(PermissionOverwriteType::Member(ctx.author().id)),
             ];
             let chan = CreateChannel::new(format!(
                 "{}-{}",
@@ -136,12 +147,32 @@ pub async fn modmail(
             .category(MOD_MAIL_CONFIG.channel_id)
             .permissions(user_perm);
             let a = ctx.guild_id().unwrap().create_channel(&ctx, chan).await?;
+            let number = TICKETS.update(|s| {
+                s.open(
+                    &a,
+                    t.clone(),
+                    category.unwrap_or_default(),
+                    ctx.author().id,
+                    ctx.author().id,
+                )
+            });
 
             let m = CreateMessage::new()
-                .content(format!("# Title: {t} \n Click here to delete the channel",))
+                .content(format!(
+                    "# Ticket {number}: {t} \n Click here to delete the channel",
+                ))
                 .button(CreateButton::new("modmail_button").label("Close Mod-Mail"));
 
             a.id.send_message(ctx, m).await?;
+            if let Err(e) = notify_staff(
+                ctx.serenity_context(),
+                a.id,
+                format!("New ticket {number}: {t} at <#{}>", a.id),
+            )
+            .await
+            {
+                eprintln!("Failed to notify staff about ticket {number}: {e}");
+            }
             ctx.reply(format!("Mod Mail Channel made at <#{}>", a.id))
                 .await?;
         }
@@ -162,6 +193,7 @@ pub async fn modmail_admin(
     ctx: Context<'_>,
     #[description = "Title"] message: Option<String>,
     #[description = "User"] suspect: Option<User>,
+    #[description = "What the ticket is about, defaults to Other"] category: Option<TicketCategory>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     match message {
@@ -171,30 +203,27 @@ pub async fn modmail_admin(
                 PermissionOverwrite {
                     allow: Permissions::empty(),
                     deny: Permissions::all(),
-                    kind: serenity::all::PermissionOverwriteType::Role(GuildId::everyone_role(
+                    kind: PermissionOverwriteType::Role(GuildId::everyone_role(
                         &ctx.guild_id().unwrap(),
                     )),
                 },
-                PermissionOverwrite {
-                    allow: Permissions::VIEW_CHANNEL
-                        | Permissions::SEND_MESSAGES
-                        | Permissions::READ_MESSAGE_HISTORY,
-                    deny: Permissions::CREATE_PUBLIC_THREADS | Permissions::CREATE_PRIVATE_THREADS,
-                    kind: serenity::all::PermissionOverwriteType::Role(MOD_MAIL_CONFIG.mod_role),
-                },
-                PermissionOverwrite {
-                    allow: Permissions::VIEW_CHANNEL
-                        | Permissions::SEND_MESSAGES
-                        | Permissions::READ_MESSAGE_HISTORY,
-                    deny: Permissions::CREATE_PUBLIC_THREADS | Permissions::CREATE_PRIVATE_THREADS,
-                    kind: serenity::all::PermissionOverwriteType::Member(suspect),
-                },
+                participant_overwrite(PermissionOverwriteType::Role(MOD_MAIL_CONFIG.mod_role)),
+                participant_overwrite(PermissionOverwriteType::Member(suspect)),
             ];
             let chan = CreateChannel::new(format!("{t}-{suspect}"))
                 .kind(serenity::all::ChannelType::Text)
                 .category(MOD_MAIL_CONFIG.channel_id)
                 .permissions(user_perm);
             let a = ctx.guild_id().unwrap().create_channel(&ctx, chan).await?;
+            TICKETS.update(|s| {
+                s.open(
+                    &a,
+                    t.clone(),
+                    category.unwrap_or_default(),
+                    suspect,
+                    ctx.author().id,
+                )
+            });
 
             let m = CreateMessage::new()
                 .content("Click here to delete the channel")
@@ -212,51 +241,931 @@ pub async fn modmail_admin(
     Ok(())
 }
 
+async fn autocomplete_snippet(_ctx: Context<'_>, partial: &str) -> Vec<String> {
+    SNIPPETS.read(|s| {
+        s.keys()
+            .filter(|k| k.starts_with(partial))
+            .cloned()
+            .collect()
+    })
+}
+
+#[poise::command(
+    slash_command,
+    default_member_permissions = "MANAGE_MESSAGES",
+    subcommands("snippet_add", "snippet_edit", "snippet_remove", "snippet_list")
+)]
+pub async fn snippet(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "add",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn snippet_add(
+    ctx: Context<'_>,
+    #[description = "Name"] name: String,
+    #[description = "Text, may use {user}, {ticket} and {mod}"] content: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let added = SNIPPETS.update(|s| {
+        if s.contains_key(&name) {
+            false
+        } else {
+            s.insert(name.clone(), content);
+            true
+        }
+    });
+    if added {
+        ctx.reply(format!("Added snippet `{name}`")).await?;
+    } else {
+        ctx.reply(format!(
+            "Snippet `{name}` already exists, use `/snippet edit`"
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "edit",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn snippet_edit(
+    ctx: Context<'_>,
+    #[description = "Name"]
+    #[autocomplete = "autocomplete_snippet"]
+    name: String,
+    #[description = "Text, may use {user}, {ticket} and {mod}"] content: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let edited = SNIPPETS.update(|s| s.get_mut(&name).map(|c| *c = content).is_some());
+    if edited {
+        ctx.reply(format!("Edited snippet `{name}`")).await?;
+    } else {
+        ctx.reply(format!("No snippet named `{name}`")).await?;
+    }
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "remove",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn snippet_remove(
+    ctx: Context<'_>,
+    #[description = "Name"]
+    #[autocomplete = "autocomplete_snippet"]
+    name: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if SNIPPETS.update(|s| s.shift_remove(&name)).is_some() {
+        ctx.reply(format!("Removed snippet `{name}`")).await?;
+    } else {
+        ctx.reply(format!("No snippet named `{name}`")).await?;
+    }
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "list",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn snippet_list(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let lines = SNIPPETS.read(|s| {
+        s.iter()
+            .map(|(name, content)| format!("**{name}**: {content}"))
+            .collect::<Vec<_>>()
+    });
+    if lines.is_empty() {
+        ctx.reply("No snippets saved").await?;
+    }
+    for chunk in chunk_lines(lines) {
+        ctx.reply(chunk).await?;
+    }
+    Ok(())
+}
+
+#[poise::command(slash_command, required_permissions = "MANAGE_MESSAGES")]
+pub async fn reply(
+    ctx: Context<'_>,
+    #[description = "Snippet"]
+    #[autocomplete = "autocomplete_snippet"]
+    snippet: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let Some(template) = SNIPPETS.read(|s| s.get(&snippet).cloned()) else {
+        ctx.reply(format!("No snippet named `{snippet}`")).await?;
+        return Ok(());
+    };
+    let Some((number, user)) =
+        TICKETS.read(|s| s.by_channel(ctx.channel_id()).map(|t| (t.number, t.user)))
+    else {
+        ctx.reply("This is not a mod-mail channel").await?;
+        return Ok(());
+    };
+
+    let sent = ctx
+        .channel_id()
+        .say(
+            ctx,
+            render_snippet(&template, user, number, ctx.author().id),
+        )
+        .await?;
+    TICKETS.update(|s| {
+        if let Some(t) = s.by_channel_mut(ctx.channel_id()) {
+            // The bot posts the snippet, so the message handler never sees it as a staff response
+            t.record_response(ctx.author().id, sent.timestamp.unix_timestamp());
+            t.log(ctx.author().id, TicketAction::Snippet { name: snippet });
+        }
+    });
+    ctx.reply("Snippet sent").await?;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    default_member_permissions = "MANAGE_MESSAGES",
+    subcommands("ticket_add", "ticket_remove", "ticket_reopen", "ticket_stats")
+)]
+pub async fn ticket(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "add",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn ticket_add(
+    ctx: Context<'_>,
+    #[description = "User to add to this ticket"] user: User,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if TICKETS.read(|s| s.by_channel(ctx.channel_id()).is_none()) {
+        ctx.reply("This is not a mod-mail channel").await?;
+        return Ok(());
+    }
+
+    ctx.channel_id()
+        .create_permission(
+            ctx,
+            participant_overwrite(PermissionOverwriteType::Member(user.id)),
+        )
+        .await?;
+    TICKETS.update(|s| {
+        if let Some(t) = s.by_channel_mut(ctx.channel_id()) {
+            if !t.participants.contains(&user.id) {
+                t.participants.push(user.id);
+            }
+            t.log(
+                ctx.author().id,
+                TicketAction::ParticipantAdded { user: user.id },
+            );
+        }
+    });
+    ctx.channel_id()
+        .say(
+            ctx,
+            format!(
+                "<@{}> added <@{}> to this ticket <t:{}:f>",
+                ctx.author().id,
+                user.id,
+                chrono::Utc::now().timestamp()
+            ),
+        )
+        .await?;
+    ctx.reply(format!("Added {}", user.name)).await?;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "remove",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn ticket_remove(
+    ctx: Context<'_>,
+    #[description = "User to remove from this ticket"] user: User,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if TICKETS.read(|s| s.by_channel(ctx.channel_id()).is_none()) {
+        ctx.reply("This is not a mod-mail channel").await?;
+        return Ok(());
+    }
+
+    ctx.channel_id()
+        .delete_permission(ctx, PermissionOverwriteType::Member(user.id))
+        .await?;
+    TICKETS.update(|s| {
+        if let Some(t) = s.by_channel_mut(ctx.channel_id()) {
+            t.participants.retain(|p| *p != user.id);
+            t.log(
+                ctx.author().id,
+                TicketAction::ParticipantRemoved { user: user.id },
+            );
+        }
+    });
+    ctx.channel_id()
+        .say(
+            ctx,
+            format!(
+                "<@{}> removed <@{}> from this ticket <t:{}:f>",
+                ctx.author().id,
+                user.id,
+                chrono::Utc::now().timestamp()
+            ),
+        )
+        .await?;
+    ctx.reply(format!("Removed {}", user.name)).await?;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "reopen",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn ticket_reopen(
+    ctx: Context<'_>,
+    #[description = "Ticket number"] number: u64,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let Some(ticket) = TICKETS.read(|s| s.tickets.iter().find(|t| t.number == number).cloned())
+    else {
+        ctx.reply(format!("No ticket number {number}")).await?;
+        return Ok(());
+    };
+    let Some(closed_at) = ticket.closed_at else {
+        ctx.reply(format!(
+            "Ticket {number} is still open at <#{}>",
+            ticket.channel_id
+        ))
+        .await?;
+        return Ok(());
+    };
+
+    let guild_id = ctx.guild_id().unwrap();
+    let mut user_perm = vec![
+        PermissionOverwrite {
+            allow: Permissions::empty(),
+            deny: Permissions::all(),
+            kind: PermissionOverwriteType::Role(GuildId::everyone_role(&guild_id)),
+        },
+        participant_overwrite(PermissionOverwriteType::Role(MOD_MAIL_CONFIG.mod_role)),
+    ];
+    user_perm.extend(
+        ticket
+            .participants
+            .iter()
+            .map(|p| participant_overwrite(PermissionOverwriteType::Member(*p))),
+    );
+    let name = if ticket.channel_name.is_empty() {
+        format!("ticket-{number}")
+    } else {
+        ticket.channel_name.clone()
+    };
+    let chan = CreateChannel::new(name)
+        .kind(serenity::all::ChannelType::Text)
+        .category(ticket.category.unwrap_or(MOD_MAIL_CONFIG.channel_id))
+        .permissions(user_perm);
+    let a = guild_id.create_channel(&ctx, chan).await?;
+
+    let mut m = CreateMessage::new()
+        .content(format!(
+            "# Ticket {number}: {} (reopened)\nOpened <t:{}:f> by <@{}>, closed <t:{closed_at}:f>, reopened by <@{}>\nClick here to delete the channel",
+            ticket.title,
+            ticket.opened_at,
+            ticket.user,
+            ctx.author().id
+        ))
+        .button(CreateButton::new("modmail_button").label("Close Mod-Mail"));
+    match std::fs::read(transcript_path(number)) {
+        Ok(transcript) => {
+            m = m.add_file(CreateAttachment::bytes(
+                transcript,
+                format!("ticket-{number}.txt"),
+            ));
+        }
+        Err(e) => eprintln!("No transcript for ticket {number}: {e}"),
+    }
+    a.id.send_message(ctx, m).await?;
+
+    TICKETS.update(|s| {
+        if let Some(t) = s.by_number_mut(number) {
+            t.channel_id = a.id;
+            t.channel_name = a.name.clone();
+            t.category = a.parent_id;
+            t.closed_at = None;
+            t.log(ctx.author().id, TicketAction::Reopened);
+        }
+    });
+    ctx.reply(format!("Ticket {number} reopened at <#{}>", a.id))
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, poise::ChoiceParameter)]
+pub enum StatsPeriod {
+    Week,
+    Month,
+    Year,
+    #[name = "All time"]
+    All,
+}
+
+#[poise::command(
+    slash_command,
+    rename = "stats",
+    required_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn ticket_stats(
+    ctx: Context<'_>,
+    #[description = "Period, defaults to the last month"] period: Option<StatsPeriod>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let now = chrono::Utc::now();
+    let (since, label) = match period.unwrap_or(StatsPeriod::Month) {
+        StatsPeriod::Week => (now - chrono::Duration::weeks(1), "the last week"),
+        StatsPeriod::Month => (now - chrono::Duration::days(30), "the last 30 days"),
+        StatsPeriod::Year => (now - chrono::Duration::days(365), "the last year"),
+        StatsPeriod::All => (chrono::DateTime::UNIX_EPOCH, "all time"),
+    };
+    let stats = TICKETS.read(|s| TicketStats::compute(&s.tickets, since.timestamp()));
+
+    let or_none = |d: Option<i64>| d.map_or("n/a".to_string(), format_duration);
+    let mut lines = vec![
+        format!("# Ticket stats for {label}"),
+        format!("Opened: {}", stats.opened),
+        format!("Closed: {}", stats.closed),
+        format!(
+            "Median time to first staff response: {}",
+            or_none(stats.median_first_response)
+        ),
+        format!("Median time to close: {}", or_none(stats.median_close)),
+        "## Per category".to_string(),
+    ];
+    lines.extend(
+        stats
+            .per_category
+            .iter()
+            .map(|(c, n)| format!("- {}: {n}", c.name())),
+    );
+    lines.push("## Per moderator (responded / closed)".to_string());
+    lines.extend(
+        stats
+            .per_moderator
+            .iter()
+            .map(|(m, (responded, closed))| format!("- <@{m}>: {responded} / {closed}")),
+    );
+
+    for chunk in chunk_lines(lines) {
+        ctx.reply(chunk).await?;
+    }
+    Ok(())
+}
+
 #[poise::command(slash_command, default_member_permissions = "ADMINISTRATOR")]
-pub async fn initrolechannel(ctx: Context<'_>) -> Result<(), Error> {
+pub async fn initrolechannel(
+    ctx: Context<'_>,
+    #[description = "Delete every message in the channel and repost the menus"] force: Option<bool>,
+) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    delete_all_messages(ctx.serenity_context(), &ctx.channel_id()).await;
-    for choices in &*ROLE_CONFIG.choices {
-        ctx.channel_id()
-            .send_message(
-                ctx,
-                CreateMessage::new()
-                    .content(choices.message.clone())
-                    .components(
-                        choices
-                            .options
-                            .iter()
-                            .collect::<Vec<_>>()
-                            .chunks(5)
-                            .map(|c| {
-                                CreateActionRow::Buttons(
-                                    c.iter()
-                                        .map(|(i, d)| {
-                                            CreateButton::new(i.to_string())
-                                        .emoji(d.emoji.parse::<ReactionType>().unwrap_or_else(
-                                            |_| {
-                                                panic!(
-                                                    "{} cannot be converted to an emoji",
-                                                    d.emoji
-                                                )
-                                            },
-                                        ))
-                                        .label(d.label.clone())
-                                        })
-                                        .collect(),
-                                )
-                            })
-                            .collect(),
-                    ),
-            )
-            .await?;
+    let problems = ROLE_CONFIG
+        .choices
+        .iter()
+        .filter_map(|c| {
+            c.check_limits()
+                .err()
+                .map(|why| format!("- {}: {why}", c.key()))
+        })
+        .collect::<Vec<_>>();
+    if !problems.is_empty() {
+        ctx.reply(format!(
+            "Fix these choices in roles.toml first:\n{}",
+            problems.join("\n")
+        ))
+        .await?;
+        return Ok(());
     }
+    let channel_id = ctx.channel_id();
+    let mut existing = if force.unwrap_or(false) {
+        delete_all_messages(ctx.serenity_context(), &channel_id).await?;
+        IndexMap::new()
+    } else {
+        ROLE_MENUS
+            .read(|s| s.get(&channel_id).cloned())
+            .unwrap_or_default()
+    };
+
+    let mut messages = IndexMap::new();
+    for choice in &ROLE_CONFIG.choices {
+        let components = role_menu_components(choice);
+        let edited = match existing.shift_remove(choice.key()) {
+            Some(id) => channel_id
+                .edit_message(
+                    ctx,
+                    id,
+                    EditMessage::new()
+                        .content(choice.message.clone())
+                        .components(components.clone()),
+                )
+                .await
+                .ok(),
+            None => None,
+        };
+        let message = match edited {
+            Some(m) => m,
+            None => {
+                channel_id
+                    .send_message(
+                        ctx,
+                        CreateMessage::new()
+                            .content(choice.message.clone())
+                            .components(components),
+                    )
+                    .await?
+            }
+        };
+        messages.insert(choice.key().to_string(), message.id);
+    }
+    // Whatever is left belongs to choices that are no longer configured
+    for id in existing.values() {
+        if let Err(e) = channel_id.delete_message(ctx, *id).await {
+            eprintln!("Failed to delete role menu {id}: {e}");
+        }
+    }
+    ROLE_MENUS.update(|s| s.insert(channel_id, messages));
+
     ctx.reply("Role channel initialised").await?;
     Ok(())
 }
 
+#[poise::command(
+    slash_command,
+    default_member_permissions = "MANAGE_ROLES",
+    subcommands("reactionrole_bind", "reactionrole_unbind")
+)]
+pub async fn reactionrole(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[poise::command(slash_command, rename = "bind", required_permissions = "MANAGE_ROLES")]
+pub async fn reactionrole_bind(
+    ctx: Context<'_>,
+    #[description = "Link to the message"] message: Message,
+    #[description = "Emoji to react with"] emoji: String,
+    #[description = "Role to give"] role: Role,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let member = ctx.author_member().await.ok_or("Not in a guild")?;
+    let checked = {
+        let guild = ctx.guild().ok_or("Guild not cached")?;
+        let top_position = member
+            .roles
+            .iter()
+            .filter_map(|r| guild.roles.get(r))
+            .map(|r| r.position)
+            .max()
+            .unwrap_or(0);
+        check_bindable(&role, top_position, guild.owner_id == member.user.id)
+    };
+    if let Err(why) = checked {
+        ctx.reply(format!("Can't bind that role: {why}")).await?;
+        return Ok(());
+    }
+
+    let Ok(reaction) = emoji.trim().parse::<ReactionType>() else {
+        ctx.reply(format!("{emoji} is not an emoji")).await?;
+        return Ok(());
+    };
+    let key = emoji_key(&reaction);
+    let bound = REACTION_ROLES.read(|s| {
+        s.iter()
+            .find(|r| r.message_id == message.id && r.emoji == key)
+            .map(|r| r.role_id)
+    });
+    if let Some(role_id) = bound {
+        ctx.reply(format!(
+            "{emoji} is already bound to <@&{role_id}> on that message"
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    message.react(ctx, reaction).await?;
+    REACTION_ROLES.update(|s| {
+        s.push(ReactionRole {
+            channel_id: message.channel_id,
+            message_id: message.id,
+            emoji: key,
+            role_id: role.id,
+        })
+    });
+    ctx.reply(format!(
+        "Reacting with {emoji} on {} now gives {}",
+        message.link(),
+        role.name
+    ))
+    .await?;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "unbind",
+    required_permissions = "MANAGE_ROLES"
+)]
+pub async fn reactionrole_unbind(
+    ctx: Context<'_>,
+    #[description = "Link to the message"] message: Message,
+    #[description = "Emoji to unbind"] emoji: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let Ok(reaction) = emoji.trim().parse::<ReactionType>() else {
+        ctx.reply(format!("{emoji} is not an emoji")).await?;
+        return Ok(());
+    };
+    let key = emoji_key(&reaction);
+    let removed = REACTION_ROLES.update(|s| {
+        let len = s.len();
+        s.retain(|r| !(r.message_id == message.id && r.emoji == key));
+        len != s.len()
+    });
+    if !removed {
+        ctx.reply(format!("{emoji} is not bound on that message"))
+            .await?;
+        return Ok(());
+    }
+
+    let me = ctx.serenity_context().cache.current_user().id;
+    if let Err(e) = message
+        .channel_id
+        .delete_reaction(ctx, message.id, Some(me), reaction)
+        .await
+    {
+        eprintln!("Failed to remove own reaction: {e}");
+    }
+    ctx.reply(format!("Unbound {emoji} from {}", message.link()))
+        .await?;
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    default_member_permissions = "MANAGE_ROLES",
+    subcommands("roles_history", "roles_stats")
+)]
+pub async fn roles(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "history",
+    required_permissions = "MANAGE_ROLES"
+)]
+pub async fn roles_history(
+    ctx: Context<'_>,
+    #[description = "Member to show self-role changes for"] user: User,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let guild_id = ctx.guild_id().unwrap();
+    let entries = ROLE_HISTORY.read(|s| {
+        s.iter()
+            .filter(|e| e.guild_id == guild_id && e.user_id == user.id)
+            .rev()
+            .take(25)
+            .map(|e| e.describe())
+            .collect::<Vec<_>>()
+    });
+
+    if entries.is_empty() {
+        ctx.reply(format!("{} has not changed any self-roles", user.name))
+            .await?;
+    } else {
+        ctx.reply(format!(
+            "# Self-role history for {} (last 60 days)\n{}",
+            user.name,
+            entries.join("\n")
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+#[poise::command(slash_command, rename = "stats", required_permissions = "MANAGE_ROLES")]
+pub async fn roles_stats(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let guild_id = ctx.guild_id().unwrap();
+    let counts = count_roles(ctx.serenity_context(), &ROLE_CONFIG, guild_id).await?;
+
+    let week_ago = (chrono::Utc::now() - chrono::Duration::weeks(1)).timestamp();
+    let previous = ROLE_SNAPSHOTS.read(|s| {
+        s.iter()
+            .filter(|snap| snap.guild_id == guild_id && snap.at <= week_ago)
+            .max_by_key(|snap| snap.at)
+            .map(|snap| snap.counts.clone())
+    });
+
+    let mut lines = vec!["# Role stats".to_string()];
+    lines.extend(render_role_stats(&ROLE_CONFIG, &counts, previous.as_ref()));
+    for chunk in chunk_lines(lines) {
+        ctx.reply(chunk).await?;
+    }
+    Ok(())
+}
+
 #[poise::command(prefix_command, default_member_permissions = "ADMINISTRATOR")]
 pub async fn register(ctx: Context<'_>) -> Result<(), Error> {
     poise::builtins::register_application_commands_buttons(ctx).await?;
     Ok(())
 }
+
+async fn autocomplete_language(
+    _ctx: Context<'_>,
+    partial: &str,
+) -> Vec<serenity::all::AutocompleteChoice> {
+    matching_languages(partial)
+        .take(25)
+        .map(|(code, name)| serenity::all::AutocompleteChoice::new(name, code))
+        .collect()
+}
+
+/// Translates a message for a command, headed by the detected source language.
+async fn translate_for(
+    ctx: Context<'_>,
+    content: &str,
+    embeds: &[Embed],
+    source: Option<&str>,
+    target: &str,
+) -> Vec<poise::CreateReply> {
+    if !within_translate_limits(Some(ctx.author().id), ctx.guild_id()) {
+        return vec![
+            poise::CreateReply::default()
+                .content("You are translating too quickly, try again in a minute"),
+        ];
+    }
+    let translator = translator(ctx).await;
+    let translated = match source {
+        Some(source) => translate_text(&*translator, content, Some(source), target)
+            .await
+            .map(|t| TranslatedMessage {
+                text: t.text,
+                embeds: vec![],
+                source: Some(source.to_string()),
+            }),
+        None => translate_message(&*translator, content, embeds, target).await,
+    };
+    let translated = match translated {
+        Ok(translated) => translated,
+        Err(e) => {
+            eprintln!("Failed to translate for {}: {e}", ctx.author().id);
+            return vec![poise::CreateReply::default().content("Unable to translate text")];
+        }
+    };
+
+    let text = match &translated.source {
+        Some(source) => format!(
+            "-# {} → {}\n{}",
+            language_name(source),
+            language_name(target),
+            translated.text
+        ),
+        None => translated.text,
+    };
+    let mut replies = split_message(&text)
+        .into_iter()
+        .map(|chunk| {
+            poise::CreateReply::default()
+                .content(chunk)
+                .allowed_mentions(CreateAllowedMentions::new())
+        })
+        .collect::<Vec<_>>();
+    if let Some(last) = replies.pop() {
+        let embeds = translated.embeds.into_iter().map(CreateEmbed::from);
+        replies.push(embeds.fold(last, |reply, embed| reply.embed(embed)));
+    }
+    replies
+}
+
+#[poise::command(context_menu_command = "Translate")]
+pub async fn translate_context_menu(ctx: Context<'_>, message: Message) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if message.content.is_empty() && message.embeds.is_empty() {
+        ctx.reply("This message has no text to translate").await?;
+        return Ok(());
+    }
+    if TRANSLATE_OPTOUTS.read(|s| s.contains(&message.author.id)) {
+        ctx.reply(format!(
+            "{} has opted out of translation",
+            message.author.display_name()
+        ))
+        .await?;
+        return Ok(());
+    }
+    let target = locale_language(ctx.locale().unwrap_or("en-US"));
+    for reply in translate_for(ctx, &message.content, &message.embeds, None, target).await {
+        ctx.send(reply).await?;
+    }
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    subcommands("translate_text_command", "translate_optout", "translate_optin")
+)]
+pub async fn translate(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[poise::command(slash_command, rename = "text")]
+pub async fn translate_text_command(
+    ctx: Context<'_>,
+    #[description = "Text to translate"] text: String,
+    #[description = "Language to translate to, defaults to yours"]
+    #[autocomplete = "autocomplete_language"]
+    target: Option<String>,
+    #[description = "Language of the text, detected if not given"]
+    #[autocomplete = "autocomplete_language"]
+    source: Option<String>,
+) -> Result<(), Error> {
+    ctx.defer().await?;
+    let target = target.unwrap_or_else(|| locale_language(ctx.locale().unwrap_or("en-US")).into());
+    for reply in translate_for(ctx, &text, &[], source.as_deref(), &target).await {
+        ctx.send(reply).await?;
+    }
+    Ok(())
+}
+
+#[poise::command(slash_command, rename = "optout")]
+pub async fn translate_optout(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let user_id = ctx.author().id;
+    TRANSLATE_OPTOUTS.update(|s| {
+        if !s.contains(&user_id) {
+            s.push(user_id);
+        }
+    });
+    ctx.reply("Your messages will no longer be sent for translation")
+        .await?;
+    Ok(())
+}
+
+#[poise::command(slash_command, rename = "optin")]
+pub async fn translate_optin(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let user_id = ctx.author().id;
+    TRANSLATE_OPTOUTS.update(|s| s.retain(|u| *u != user_id));
+    ctx.reply("Your messages can be translated again").await?;
+    Ok(())
+}
+
+async fn translator(ctx: Context<'_>) -> Arc<dyn Translator> {
+    ctx.serenity_context()
+        .data
+        .read()
+        .await
+        .get::<TranslatorKey>()
+        .unwrap()
+        .clone()
+}
+
+/// Detects the language of `text` and describes the most likely candidates.
+async fn detect_for(ctx: Context<'_>, text: &str) -> String {
+    let plain = protect(text).plain();
+    if !plain.chars().any(char::is_alphabetic) {
+        return "There is no text to detect a language in".to_string();
+    }
+    if !within_translate_limits(Some(ctx.author().id), ctx.guild_id()) {
+        return "You are translating too quickly, try again in a minute".to_string();
+    }
+    match translator(ctx).await.detect(&plain).await {
+        Ok(detections) if detections.is_empty() => "No language detected".to_string(),
+        Ok(detections) => detections
+            .iter()
+            .take(3)
+            .map(|d| match d.confidence {
+                Some(confidence) => format!("{} ({confidence:.0}%)", language_name(&d.language)),
+                None => language_name(&d.language).to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(e) => {
+            eprintln!("Failed to detect language for {}: {e}", ctx.author().id);
+            "Unable to detect the language".to_string()
+        }
+    }
+}
+
+#[poise::command(
+    slash_command,
+    subcommands("detect_text", "detect_message", "detect_stats")
+)]
+pub async fn detect(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[poise::command(slash_command, rename = "text")]
+pub async fn detect_text(
+    ctx: Context<'_>,
+    #[description = "Text to detect the language of"] text: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let reply = detect_for(ctx, &text).await;
+    ctx.reply(reply).await?;
+    Ok(())
+}
+
+#[poise::command(slash_command, rename = "message")]
+pub async fn detect_message(
+    ctx: Context<'_>,
+    #[description = "Message link or ID"] message: Message,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if TRANSLATE_OPTOUTS.read(|s| s.contains(&message.author.id)) {
+        ctx.reply(format!(
+            "{} has opted out of translation",
+            message.author.display_name()
+        ))
+        .await?;
+        return Ok(());
+    }
+    let reply = detect_for(ctx, &message.content).await;
+    ctx.reply(reply).await?;
+    Ok(())
+}
+
+/// Samples recent messages, one backend request each, within the guild's sample limit.
+#[poise::command(
+    slash_command,
+    rename = "stats",
+    required_permissions = "MANAGE_CHANNELS"
+)]
+pub async fn detect_stats(
+    ctx: Context<'_>,
+    #[description = "Channel to sample, defaults to this one"] channel: Option<GuildChannel>,
+    #[description = "How many recent messages to sample, defaults to 50"]
+    #[min = 1]
+    #[max = 100]
+    sample: Option<usize>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let channel_id = channel.map_or(ctx.channel_id(), |c| c.id);
+    let translator = translator(ctx).await;
+    let optouts = TRANSLATE_OPTOUTS.read(|s| s.clone());
+
+    let mut messages = channel_id
+        .messages_iter(ctx)
+        .take(sample.unwrap_or(50))
+        .boxed();
+    let mut languages = vec![];
+    let mut limited = false;
+    while let Some(m) = messages.next().await {
+        let m = m?;
+        if m.author.bot || optouts.contains(&m.author.id) {
+            continue;
+        }
+        let protected = protect(&m.content);
+        if !protected.has_text() {
+            continue;
+        }
+        if !within_sample_limit(ctx.guild_id()) {
+            limited = true;
+            break;
+        }
+        match translator.detect(&protected.plain()).await {
+            Ok(detections) => languages.extend(detections.into_iter().next().map(|d| d.language)),
+            Err(e) => eprintln!("Failed to detect language of {}: {e}", m.id),
+        }
+    }
+
+    if languages.is_empty() && limited {
+        ctx.reply("The server's sampling limit was reached, try again in a minute")
+            .await?;
+        return Ok(());
+    }
+    if languages.is_empty() {
+        ctx.reply(format!("No messages with text found in <#{channel_id}>"))
+            .await?;
+        return Ok(());
+    }
+    let mut lines = vec![format!(
+        "Languages of {} recent messages in <#{channel_id}>:",
+        languages.len()
+    )];
+    lines.extend(render_language_stats(&languages));
+    if limited {
+        lines.push("Stopped early, the server's sampling limit was reached".to_string());
+    }
+    for chunk in chunk_lines(lines) {
+        ctx.reply(chunk).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+
+use poise::serenity_prelude as serenity;
+use serenity::all::{CreateAllowedMentions, CreateMessage};
+
+use crate::{ERROR_CONFIG, Error, translate::truncate_message};
+
+/// Everything that can go wrong while handling a gateway event.
+#[derive(Debug)]
+pub enum HandlerError {
+    Discord(serenity::Error),
+    Translation(Error),
+    /// The event lacked something Discord always sends for it, such as the member of a guild interaction.
+    Missing(&'static str),
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::Discord(e) => write!(f, "Discord request failed: {e}"),
+            HandlerError::Translation(e) => write!(f, "Translation failed: {e}"),
+            HandlerError::Missing(what) => write!(f, "Event has no {what}"),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HandlerError::Discord(e) => Some(e),
+            HandlerError::Translation(e) => Some(e.as_ref()),
+            HandlerError::Missing(_) => None,
+        }
+    }
+}
+
+impl From<serenity::Error> for HandlerError {
+    fn from(e: serenity::Error) -> Self {
+        HandlerError::Discord(e)
+    }
+}
+
+/// Logs a failed event and posts it to the channel in `errors.toml`, if there is one.
+pub async fn report(ctx: &serenity::Context, event: &str, result: Result<(), HandlerError>) {
+    let Err(e) = result else {
+        return;
+    };
+    eprintln!("Failed to handle {event}: {e}");
+
+    if let Some(config) = &*ERROR_CONFIG
+        && let Err(post) = config
+            .channel_id
+            .send_message(
+                ctx,
+                CreateMessage::new()
+                    .content(truncate_message(&format!(
+                        "Failed to handle `{event}`: {e}"
+                    )))
+                    .allowed_mentions(CreateAllowedMentions::new()),
+            )
+            .await
+    {
+        eprintln!("Failed to report error to {}: {post}", config.channel_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_test() {
+        assert_eq!(
+            HandlerError::Missing("member").to_string(),
+            "Event has no member"
+        );
+        let e = HandlerError::Translation("timed out".into());
+        assert_eq!(e.to_string(), "Translation failed: timed out");
+        assert_eq!(
+            std::error::Error::source(&e).map(|s| s.to_string()),
+            Some("timed out".to_string())
+        );
+    }
+}
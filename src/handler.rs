@@ -1,157 +1,1195 @@
-use std::{env, fs};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
 
 use ::serenity::{
     all::{
-        ChannelId, CreateInteractionResponse, CreateInteractionResponseMessage, EditMessage,
-        EventHandler, Interaction, Reaction, ReactionType, Ready,
+        ChannelId, ComponentInteraction, CreateAllowedMentions, CreateEmbed,
+        CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage, CreateThread,
+        CreateWebhook, EditMessage, EventHandler, ExecuteWebhook, GuildId, GuildMemberUpdateEvent,
+        Interaction, Member, Message, MessageId, Reaction, ReactionType, Ready, RoleId, Timestamp,
+        User, UserId, Webhook,
     },
     async_trait,
     futures::StreamExt,
 };
 use poise::serenity_prelude as serenity;
-use serde::Deserialize;
 
-use crate::{ROLE_MAP, read_conf::VerificationConfig};
+use crate::{
+    CURRENT_TRACKED_ROLES, DEPARTED_MEMBER_ROLES, MOD_MAIL_CONFIG, REACTION_ROLES, ROLE_CONFIG,
+    ROLE_EXPIRIES, ROLE_HISTORY, ROLE_SNAPSHOTS, STICKY_ROLE_CONFIG, TICKETS, TRANSLATE_CONFIG,
+    TRANSLATE_OPTOUTS, TranslatorKey,
+    errors::{HandlerError, report},
+    read_conf::{
+        AutoTranslateChannel, AutoTranslateOutput, ReactionDelivery, StickyRoleConfig,
+        VerificationConfig,
+    },
+    roles::{
+        CurrentMemberRoles, DepartedMemberRoles, RoleExpiry, RoleLogEntry, RoleSnapshot,
+        count_roles, describe_change, emoji_key, select_roles, toggle_role,
+    },
+    router::{Component, route},
+    tickets::{TicketAction, archive_transcript, notify_staff},
+    translate::{
+        LANGUAGES, Lru, RateLimiter, TranslatedMessage, TranslationReply, flag_country,
+        flag_language, language_name, same_language, split_message, translate_message,
+        translate_text, truncate_message,
+    },
+};
 
 use super::read_conf::PurgeTimerConfig;
 
-#[allow(non_snake_case)]
-#[derive(Deserialize)]
-struct Translation {
-    translatedText: String,
-}
-
 pub struct Handler;
 
-pub async fn delete_all_messages(ctx: &serenity::Context, channel_id: &ChannelId) {
+static PENDING_ROLE_LOG: LazyLock<Mutex<Vec<RoleLogEntry>>> = LazyLock::new(|| Mutex::new(vec![]));
+
+static REPORTED_COMPONENTS: LazyLock<Mutex<HashSet<(ChannelId, String)>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+type DmTranslationKey = (MessageId, UserId, String);
+
+static DM_TRANSLATIONS: LazyLock<Mutex<Lru<DmTranslationKey, (ChannelId, MessageId)>>> =
+    LazyLock::new(|| Mutex::new(Lru::new(TRANSLATE_CONFIG.cache_size)));
+
+static TRANSLATION_WEBHOOKS: LazyLock<Mutex<HashMap<ChannelId, Webhook>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+type TranslationKey = (MessageId, Option<Timestamp>, String);
+
+static TRANSLATIONS: LazyLock<Mutex<Lru<TranslationKey, TranslatedMessage>>> =
+    LazyLock::new(|| Mutex::new(Lru::new(TRANSLATE_CONFIG.cache_size)));
+
+static TRANSLATION_REPLIES: LazyLock<Mutex<Lru<MessageId, TranslationReply>>> =
+    LazyLock::new(|| Mutex::new(Lru::new(TRANSLATE_CONFIG.cache_size)));
+
+/// Messages that already got an unknown-flag notice, so each gets at most one.
+static FLAG_NOTICES: LazyLock<Mutex<Lru<MessageId, ()>>> =
+    LazyLock::new(|| Mutex::new(Lru::new(TRANSLATE_CONFIG.cache_size)));
+
+static USER_TRANSLATE_LIMIT: LazyLock<Mutex<RateLimiter<UserId>>> = LazyLock::new(|| {
+    Mutex::new(RateLimiter::new(
+        TRANSLATE_CONFIG.rate_limit.user_per_minute,
+        Duration::from_secs(60),
+    ))
+});
+
+static GUILD_TRANSLATE_LIMIT: LazyLock<Mutex<RateLimiter<GuildId>>> = LazyLock::new(|| {
+    Mutex::new(RateLimiter::new(
+        TRANSLATE_CONFIG.rate_limit.guild_per_minute,
+        Duration::from_secs(60),
+    ))
+});
+
+static SAMPLE_LIMIT: LazyLock<Mutex<RateLimiter<GuildId>>> = LazyLock::new(|| {
+    Mutex::new(RateLimiter::new(
+        TRANSLATE_CONFIG.rate_limit.sample_per_minute,
+        Duration::from_secs(60),
+    ))
+});
+
+pub async fn delete_all_messages(
+    ctx: &serenity::Context,
+    channel_id: &ChannelId,
+) -> Result<(), HandlerError> {
     loop {
         let mut messages = channel_id.messages_iter(&ctx).boxed();
         let mut m_vec = vec![];
-        while let Some(m_res) = messages.next().await {
-            if let Ok(m) = m_res {
-                m_vec.push(m);
-            }
+        while let Some(m) = messages.next().await {
+            m_vec.push(m?);
         }
         if m_vec.is_empty() {
-            break;
+            return Ok(());
         }
-        channel_id.delete_messages(&ctx, m_vec).await.unwrap();
+        channel_id.delete_messages(&ctx, m_vec).await?;
     }
 }
 
-async fn translate(message: String, target_codepoints: Vec<char>) -> Option<String> {
-    let url = env::var("TRANSLATE_URL").expect("Expected a translation url in the environment");
-    let target_lang = match (target_codepoints[0], target_codepoints[1]) {
-        ('\u{1f1f8}', '\u{1f1ea}') => "sv", // SE Swedish
-        ('\u{1f1ec}', '\u{1f1e7}') => "en", // GB English
-        ('\u{1f1eb}', '\u{1f1f7}') => "fr", // FR French
-        ('\u{1f1f0}', '\u{1f1f7}') => "ko", // KR Korean
-        _ => return None,
-    };
-    let client = reqwest::Client::new();
-    let res = client
-        .post(url)
-        .json(&serde_json::json!({
-            "q": message,
-            "source": "auto",
-            "target": target_lang,
-        }))
-        .send()
-        .await;
-    Some(res.ok()?.json::<Translation>().await.ok()?.translatedText)
-}
-
-pub async fn delete_all_messages_except_mine(ctx: &serenity::Context, channel_id: &ChannelId) {
+pub async fn delete_all_messages_except_mine(
+    ctx: &serenity::Context,
+    channel_id: &ChannelId,
+) -> Result<(), HandlerError> {
     loop {
         let mut messages = channel_id.messages_iter(&ctx).boxed();
         let mut m_vec = vec![];
-        while let Some(m_res) = messages.next().await {
-            if let Ok(m) = m_res
-                && !m.is_own(ctx)
-            {
+        while let Some(m) = messages.next().await {
+            let m = m?;
+            if m.author.id != ctx.cache.current_user().id {
                 m_vec.push(m);
             }
         }
         if m_vec.is_empty() {
-            break;
+            return Ok(());
         }
-        channel_id.delete_messages(&ctx, m_vec).await.unwrap();
+        channel_id.delete_messages(&ctx, m_vec).await?;
     }
 }
 
-#[async_trait]
-impl EventHandler for Handler {
-    async fn reaction_add(&self, ctx: serenity::Context, add_reaction: Reaction) {
-        if let ReactionType::Unicode(code) = &add_reaction.emoji {
-            let bytes: Vec<char> = code.chars().collect();
-            if bytes[0] <= '\u{1f1ff}' && bytes[0] >= '\u{1f1e6}' {
-                let m = add_reaction.message(&ctx).await.unwrap();
-                let reply = translate(m.content.clone(), bytes)
-                    .await
-                    .unwrap_or_else(|| "Unable to translate text".to_string());
-                m.reply(ctx, reply).await.unwrap();
+/// Gives the verified role to everyone who joined more than `period` seconds ago.
+async fn verify_members(
+    ctx: &serenity::Context,
+    guild_id: GuildId,
+    verified_role_id: RoleId,
+    period: i64,
+) -> Result<(), HandlerError> {
+    let now_timestamp = chrono::Utc::now().timestamp();
+    let all_members = guild_id.members(ctx, None, None).await?;
+    let unverified_members = all_members
+        .iter()
+        .filter(|m| !m.roles.contains(&verified_role_id));
+    for m in unverified_members {
+        if let Some(joined) = m.joined_at
+            && now_timestamp - joined.timestamp() > period
+        {
+            println!("{} has been verified", m.display_name());
+            m.add_roles(ctx, &[verified_role_id]).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Clears the purge channel and updates the bot's notice with the next purge time.
+async fn purge_channel(ctx: &serenity::Context, channel_id: ChannelId) -> Result<(), HandlerError> {
+    delete_all_messages_except_mine(ctx, &channel_id).await?;
+
+    let next_purge = (chrono::Utc::now() + chrono::Duration::days(1)).timestamp();
+    if let Some(mut notice) = channel_id
+        .messages_iter(ctx)
+        .boxed()
+        .next()
+        .await
+        .transpose()?
+    {
+        notice
+            .edit(
+                ctx,
+                EditMessage::new().content(format!("Channel will be purged in <t:{next_purge}:R>")),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+fn bound_role(reaction: &Reaction) -> Option<RoleId> {
+    let key = emoji_key(&reaction.emoji);
+    REACTION_ROLES.read(|s| {
+        s.iter()
+            .find(|r| r.message_id == reaction.message_id && r.emoji == key)
+            .map(|r| r.role_id)
+    })
+}
+
+async fn expire_roles(ctx: &serenity::Context) {
+    let now = chrono::Utc::now().timestamp();
+    let expired = ROLE_EXPIRIES.update(|s| {
+        let (expired, kept) = s.drain(..).partition(|e| e.expires_at <= now);
+        *s = kept;
+        expired
+    });
+    let mut failed = vec![];
+    for e in expired {
+        if let Err(err) = ctx
+            .http
+            .remove_member_role(e.guild_id, e.user_id, e.role_id, Some("Role expired"))
+            .await
+        {
+            // The member or role is gone, there is nothing left to remove
+            let gone = matches!(
+                &err,
+                serenity::Error::Http(serenity::HttpError::UnsuccessfulRequest(r))
+                    if r.status_code.as_u16() == 404
+            );
+            eprintln!(
+                "Failed to remove expired role {} from {}: {err}",
+                e.role_id, e.user_id
+            );
+            if !gone {
+                failed.push(e);
             }
         }
     }
+    if !failed.is_empty() {
+        ROLE_EXPIRIES.update(|s| s.extend(failed));
+    }
+}
 
-    async fn interaction_create(&self, ctx: serenity::Context, interaction: Interaction) {
-        let interaction = match interaction {
-            Interaction::Component(i) => i,
-            _ => {
-                eprintln!("Unimplemented interaction: {interaction:?}");
-                return;
+async fn close_ticket(
+    ctx: &serenity::Context,
+    interaction: &ComponentInteraction,
+) -> Result<(), HandlerError> {
+    let number = TICKETS.read(|s| s.by_channel(interaction.channel_id).map(|t| t.number));
+    if let Some(number) = number
+        && let Err(e) = archive_transcript(ctx, interaction.channel_id, number).await
+    {
+        eprintln!("Failed to archive ticket {number}: {e}");
+    }
+    TICKETS.update(|s| {
+        if let Some(t) = s.by_channel_mut(interaction.channel_id) {
+            t.log(interaction.user.id, TicketAction::Closed);
+            t.closed_at = Some(chrono::Utc::now().timestamp());
+        }
+    });
+    interaction.channel_id.delete(ctx).await?;
+    Ok(())
+}
+
+async fn outdated_component(ctx: &serenity::Context, interaction: &ComponentInteraction) {
+    let custom_id = &interaction.data.custom_id;
+    eprintln!(
+        "Outdated component {custom_id} used by {} in {}",
+        interaction.user.name, interaction.channel_id
+    );
+
+    if let Err(e) = interaction
+        .create_response(
+            ctx,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::default()
+                    .ephemeral(true)
+                    .content("This menu is outdated, please ask an admin to refresh it"),
+            ),
+        )
+        .await
+    {
+        eprintln!("Failed to respond to outdated component {custom_id}: {e}");
+    }
+
+    let first_report = REPORTED_COMPONENTS
+        .lock()
+        .unwrap()
+        .insert((interaction.channel_id, custom_id.clone()));
+    if let Some(admin_channel) = ROLE_CONFIG.admin_channel
+        && first_report
+        && let Err(e) = admin_channel
+            .say(
+                ctx,
+                format!(
+                    "Someone used an outdated menu (`{custom_id}`) in <#{}>, re-run `/initrolechannel` there",
+                    interaction.channel_id
+                ),
+            )
+            .await
+    {
+        eprintln!("Failed to notify admins about {custom_id}: {e}");
+    }
+}
+
+/// Joins `lines` into messages that fit in Discord's 2000 character limit.
+pub fn chunk_lines(lines: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut chunks: Vec<String> = vec![];
+    for line in lines {
+        match chunks.last_mut() {
+            Some(chunk) if chunk.len() + line.len() < 2000 => {
+                chunk.push('\n');
+                chunk.push_str(&line);
             }
-        };
+            _ => chunks.push(line),
+        }
+    }
+    chunks
+}
+
+async fn flush_role_log(ctx: &serenity::Context, channel_id: ChannelId) {
+    let entries = std::mem::take(&mut *PENDING_ROLE_LOG.lock().unwrap());
+    for chunk in chunk_lines(entries.iter().map(RoleLogEntry::describe)) {
+        if let Err(e) = channel_id
+            .send_message(
+                ctx,
+                CreateMessage::new()
+                    .content(chunk)
+                    .allowed_mentions(CreateAllowedMentions::new()),
+            )
+            .await
+        {
+            eprintln!("Failed to post role log: {e}");
+        }
+    }
+}
+
+async fn snapshot_roles(ctx: &serenity::Context, guild_id: GuildId) {
+    match count_roles(ctx, &ROLE_CONFIG, guild_id).await {
+        Ok(counts) => {
+            let now = chrono::Utc::now().timestamp();
+            let cutoff = (chrono::Utc::now() - chrono::Duration::days(60)).timestamp();
+            ROLE_SNAPSHOTS.update(|s| {
+                s.retain(|snap| snap.at > cutoff);
+                s.push(RoleSnapshot {
+                    guild_id,
+                    at: now,
+                    counts,
+                });
+            });
+        }
+        Err(e) => eprintln!("Failed to snapshot roles in {guild_id}: {e}"),
+    }
+}
+
+fn daily_interval(hour: u8, minute: u8, second: u8) -> tokio::time::Interval {
+    let now = chrono::Utc::now();
+    let mut start = now
+        .date_naive()
+        .and_hms_opt(hour.into(), minute.into(), second.into())
+        .unwrap()
+        .signed_duration_since(now.naive_utc());
+    let period = chrono::Duration::hours(24).to_std().unwrap();
 
-        if &interaction.data.custom_id == "modmail_button" {
-            interaction.channel_id.delete(ctx).await.unwrap();
+    if start < chrono::Duration::zero() {
+        start = start.checked_add(&chrono::Duration::hours(24)).unwrap();
+    }
+
+    tokio::time::interval_at(
+        tokio::time::Instant::now() + start.to_std().unwrap(),
+        period,
+    )
+}
+
+async fn escalate_tickets(ctx: &serenity::Context, after_minutes: i64) {
+    let now = chrono::Utc::now().timestamp();
+    let overdue = TICKETS.update(|s| {
+        s.tickets
+            .iter_mut()
+            .filter(|t| {
+                t.closed_at.is_none()
+                    && t.first_response_at.is_none()
+                    && !t.escalated
+                    && now - t.opened_at > after_minutes * 60
+            })
+            .map(|t| {
+                t.escalated = true;
+                (t.number, t.channel_id)
+            })
+            .collect::<Vec<_>>()
+    });
+    for (number, channel_id) in overdue {
+        let content = format!(
+            "Ticket {number} at <#{channel_id}> has been unanswered for over {after_minutes} minutes"
+        );
+        if let Err(e) = notify_staff(ctx, channel_id, content).await {
+            eprintln!("Failed to escalate ticket {number}: {e}");
+        }
+    }
+}
+
+async fn post_ticket_digest(ctx: &serenity::Context, channel_id: ChannelId) {
+    let mut open = TICKETS.read(|s| {
+        s.tickets
+            .iter()
+            .filter(|t| t.closed_at.is_none())
+            .map(|t| (t.opened_at, t.number, t.title.clone(), t.channel_id))
+            .collect::<Vec<_>>()
+    });
+    open.sort();
+
+    let mut lines = vec!["# Open tickets".to_string()];
+    if open.is_empty() {
+        lines.push("No open tickets".to_string());
+    }
+    lines.extend(open.iter().map(|(opened_at, number, title, channel)| {
+        format!("- Ticket {number}: {title} <#{channel}> opened <t:{opened_at}:R>")
+    }));
+    for chunk in chunk_lines(lines) {
+        // Titles come from users, never let them ping anyone
+        if let Err(e) = channel_id
+            .send_message(
+                ctx,
+                CreateMessage::new()
+                    .content(chunk)
+                    .allowed_mentions(CreateAllowedMentions::new()),
+            )
+            .await
+        {
+            eprintln!("Failed to post ticket digest: {e}");
             return;
         }
+    }
+}
+
+/// Whether the user may trigger another backend request, recording it if so.
+pub fn within_translate_limits(user_id: Option<UserId>, guild_id: Option<GuildId>) -> bool {
+    let now = Instant::now();
+    let mut users = USER_TRANSLATE_LIMIT.lock().unwrap();
+    let mut guilds = GUILD_TRANSLATE_LIMIT.lock().unwrap();
+    let allowed = user_id.is_none_or(|u| users.allows(&u, now))
+        && guild_id.is_none_or(|g| guilds.allows(&g, now));
+    if allowed {
+        if let Some(user_id) = user_id {
+            users.record(user_id, now);
+        }
+        if let Some(guild_id) = guild_id {
+            guilds.record(guild_id, now);
+        }
+    }
+    allowed
+}
 
-        let sel_role = &interaction
-            .guild_id
-            .unwrap()
-            .role(
-                &ctx,
-                *ROLE_MAP
-                    .get(&interaction.data.custom_id)
-                    .unwrap_or_else(|| panic!()),
+/// Whether `/detect stats` may sample another message in the guild, recording it if so.
+pub fn within_sample_limit(guild_id: Option<GuildId>) -> bool {
+    let Some(guild_id) = guild_id else {
+        return true;
+    };
+    let now = Instant::now();
+    let mut guilds = SAMPLE_LIMIT.lock().unwrap();
+    let allowed = guilds.allows(&guild_id, now);
+    if allowed {
+        guilds.record(guild_id, now);
+    }
+    allowed
+}
+
+async fn translate_reaction(
+    ctx: &serenity::Context,
+    reaction: &Reaction,
+    m: &Message,
+    lang: String,
+) -> Result<(), HandlerError> {
+    if m.content.is_empty() && m.embeds.is_empty() {
+        return Ok(());
+    }
+    let delivery = TRANSLATE_CONFIG.reactions.delivery;
+    if delivery != ReactionDelivery::Dm {
+        let mut replies = TRANSLATION_REPLIES.lock().unwrap();
+        if let Some(reply) = replies.get(&m.id)
+            && reply.shows(m.edited_timestamp, &lang)
+        {
+            reply.request(&lang, reaction.user_id);
+            return Ok(());
+        }
+    }
+
+    let key = (m.id, m.edited_timestamp, lang.clone());
+    let cached = TRANSLATIONS.lock().unwrap().get(&key).cloned();
+    let translated = match cached {
+        Some(translated) => translated,
+        None => {
+            if !within_translate_limits(reaction.user_id, reaction.guild_id) {
+                return Ok(());
+            }
+            let translator = ctx
+                .data
+                .read()
+                .await
+                .get::<TranslatorKey>()
+                .unwrap()
+                .clone();
+            match translate_message(&*translator, &m.content, &m.embeds, &lang).await {
+                Ok(translated) => {
+                    TRANSLATIONS.lock().unwrap().insert(key, translated.clone());
+                    translated
+                }
+                Err(e) => {
+                    m.reply(ctx, "Unable to translate text").await?;
+                    return Err(HandlerError::Translation(e));
+                }
+            }
+        }
+    };
+
+    match delivery {
+        ReactionDelivery::Dm => dm_translation(ctx, reaction, m, lang, translated).await,
+        _ => post_translation(ctx, reaction, m, lang, translated).await,
+    }
+}
+
+/// Sends the reactor a translation, remembering it so it can be taken back on unreact.
+async fn dm_translation(
+    ctx: &serenity::Context,
+    reaction: &Reaction,
+    m: &Message,
+    lang: String,
+    translated: TranslatedMessage,
+) -> Result<(), HandlerError> {
+    let user_id = reaction.user_id.ok_or(HandlerError::Missing("user"))?;
+    let embeds = translated.embeds.into_iter().map(CreateEmbed::from);
+    let dm = user_id
+        .direct_message(
+            ctx,
+            CreateMessage::new()
+                .content(truncate_message(&format!(
+                    "-# {} of {}\n{}",
+                    language_name(&lang),
+                    m.link(),
+                    translated.text
+                )))
+                .embeds(embeds.take(10).collect())
+                .allowed_mentions(CreateAllowedMentions::new()),
+        )
+        .await?;
+    DM_TRANSLATIONS
+        .lock()
+        .unwrap()
+        .insert((m.id, user_id, lang), (dm.channel_id, dm.id));
+    Ok(())
+}
+
+/// Adds the translation to the message's existing translation reply, or posts one.
+async fn post_translation(
+    ctx: &serenity::Context,
+    reaction: &Reaction,
+    m: &Message,
+    lang: String,
+    translated: TranslatedMessage,
+) -> Result<(), HandlerError> {
+    let (existing, content, embeds) = {
+        let mut replies = TRANSLATION_REPLIES.lock().unwrap();
+        if replies.get(&m.id).is_none() {
+            replies.insert(m.id, TranslationReply::default());
+        }
+        let reply = replies.get(&m.id).unwrap();
+        reply.add(m.edited_timestamp, lang, translated, reaction.user_id);
+        (reply.reply, reply.render(), reply.embeds())
+    };
+    let embeds = embeds
+        .into_iter()
+        .map(CreateEmbed::from)
+        .collect::<Vec<_>>();
+
+    if let Some((channel_id, reply)) = existing
+        && channel_id
+            .edit_message(
+                ctx,
+                reply,
+                EditMessage::new()
+                    .content(&content)
+                    .embeds(embeds.clone())
+                    .allowed_mentions(CreateAllowedMentions::new()),
             )
             .await
-            .unwrap();
+            .is_ok()
+    {
+        return Ok(());
+    }
+
+    let delivery = TRANSLATE_CONFIG.reactions.delivery;
+    let mut message = CreateMessage::new()
+        .content(content)
+        .embeds(embeds)
+        .allowed_mentions(CreateAllowedMentions::new());
+    let channel_id = match delivery {
+        ReactionDelivery::Thread => match &m.thread {
+            Some(thread) => thread.id,
+            None => {
+                m.channel_id
+                    .create_thread_from_message(ctx, m.id, CreateThread::new("Translations"))
+                    .await?
+                    .id
+            }
+        },
+        _ => {
+            message = message.reference_message(m);
+            m.channel_id
+        }
+    };
+    let reply = channel_id.send_message(ctx, message).await?;
+    if let Some(r) = TRANSLATION_REPLIES.lock().unwrap().get(&m.id) {
+        r.reply = Some((channel_id, reply.id));
+    }
+
+    if let ReactionDelivery::Expiring { minutes } = delivery {
+        let ctx = ctx.clone();
+        let message_id = m.id;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(minutes * 60)).await;
+            {
+                let mut replies = TRANSLATION_REPLIES.lock().unwrap();
+                if replies
+                    .get(&message_id)
+                    .is_some_and(|r| r.reply == Some((channel_id, reply.id)))
+                {
+                    replies.remove(&message_id);
+                }
+            }
+            let result = channel_id.delete_message(&ctx, reply.id).await;
+            report(&ctx, "translation expiry", result.map_err(Into::into)).await;
+        });
+    }
+    Ok(())
+}
+
+/// Takes down `user`'s translation of a message into `lang`, once nobody else wants it.
+async fn withdraw_translation(
+    ctx: &serenity::Context,
+    message_id: MessageId,
+    user_id: UserId,
+    lang: String,
+) -> Result<(), HandlerError> {
+    let dm = DM_TRANSLATIONS
+        .lock()
+        .unwrap()
+        .remove(&(message_id, user_id, lang.clone()));
+    if let Some((channel_id, dm)) = dm {
+        channel_id.delete_message(ctx, dm).await?;
+        return Ok(());
+    }
 
-        let mem = interaction.member.clone().unwrap();
+    let update = {
+        let mut replies = TRANSLATION_REPLIES.lock().unwrap();
+        let Some(reply) = replies.get(&message_id) else {
+            return Ok(());
+        };
+        if !reply.withdraw(&lang, user_id) {
+            return Ok(());
+        }
+        let Some(location) = reply.reply else {
+            return Ok(());
+        };
+        if reply.translations.is_empty() {
+            replies.remove(&message_id);
+            (location, None)
+        } else {
+            (location, Some((reply.render(), reply.embeds())))
+        }
+    };
+
+    match update {
+        ((channel_id, reply), None) => channel_id.delete_message(ctx, reply).await?,
+        ((channel_id, reply), Some((content, embeds))) => {
+            channel_id
+                .edit_message(
+                    ctx,
+                    reply,
+                    EditMessage::new()
+                        .content(content)
+                        .embeds(embeds.into_iter().map(CreateEmbed::from).collect())
+                        .allowed_mentions(CreateAllowedMentions::new()),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// The webhook used to repost translations in `channel_id`, created on first use.
+async fn translation_webhook(
+    ctx: &serenity::Context,
+    channel_id: ChannelId,
+) -> Result<Webhook, serenity::Error> {
+    if let Some(webhook) = TRANSLATION_WEBHOOKS.lock().unwrap().get(&channel_id) {
+        return Ok(webhook.clone());
+    }
+    let me = ctx.cache.current_user().id;
+    let existing = channel_id
+        .webhooks(ctx)
+        .await?
+        .into_iter()
+        .find(|w| w.token.is_some() && w.user.as_ref().is_some_and(|u| u.id == me));
+    let webhook = match existing {
+        Some(webhook) => webhook,
+        None => {
+            channel_id
+                .create_webhook(ctx, CreateWebhook::new("Translations"))
+                .await?
+        }
+    };
+    TRANSLATION_WEBHOOKS
+        .lock()
+        .unwrap()
+        .insert(channel_id, webhook.clone());
+    Ok(webhook)
+}
 
-        let reply = if interaction
-            .user
-            .has_role(&ctx, &interaction.guild_id.unwrap(), sel_role)
+async fn auto_translate(
+    ctx: &serenity::Context,
+    auto: &AutoTranslateChannel,
+    m: &Message,
+) -> Result<(), HandlerError> {
+    if m.content.is_empty() || TRANSLATE_OPTOUTS.read(|s| s.contains(&m.author.id)) {
+        return Ok(());
+    }
+    let translator = ctx
+        .data
+        .read()
+        .await
+        .get::<TranslatorKey>()
+        .unwrap()
+        .clone();
+    let [first, second] = &auto.languages;
+    let within_limits = || within_translate_limits(Some(m.author.id), m.guild_id);
+    if !within_limits() {
+        return Ok(());
+    }
+    let mut translated = translate_text(&*translator, &m.content, None, second)
+        .await
+        .map_err(HandlerError::Translation)?;
+    if translated
+        .source
+        .as_deref()
+        .is_some_and(|s| same_language(s, second))
+    {
+        if !within_limits() {
+            return Ok(());
+        }
+        translated = translate_text(&*translator, &m.content, Some(second), first)
             .await
-            .unwrap()
+            .map_err(HandlerError::Translation)?;
+    }
+    if translated.text.trim() == m.content.trim() {
+        return Ok(());
+    }
+
+    match auto.output {
+        AutoTranslateOutput::Webhook => {
+            let webhook = translation_webhook(ctx, m.channel_id).await?;
+            let name = m
+                .author_nick(ctx)
+                .await
+                .unwrap_or_else(|| m.author.display_name().to_string());
+            for chunk in split_message(&translated.text) {
+                webhook
+                    .execute(
+                        ctx,
+                        false,
+                        ExecuteWebhook::new()
+                            .content(chunk)
+                            .username(&name)
+                            .avatar_url(m.author.face())
+                            .allowed_mentions(CreateAllowedMentions::new()),
+                    )
+                    .await?;
+            }
+        }
+        AutoTranslateOutput::Thread => {
+            let thread = m
+                .channel_id
+                .create_thread_from_message(ctx, m.id, CreateThread::new("Translation"))
+                .await?;
+            for chunk in split_message(&translated.text) {
+                thread
+                    .send_message(
+                        ctx,
+                        CreateMessage::new()
+                            .content(chunk)
+                            .allowed_mentions(CreateAllowedMentions::new()),
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps a member's tracked roles on record, writing only when they changed.
+fn record_tracked_roles(
+    config: &StickyRoleConfig,
+    guild_id: GuildId,
+    user_id: UserId,
+    roles: &[RoleId],
+) {
+    let tracked = config.tracked(roles);
+    let recorded = CURRENT_TRACKED_ROLES.read(|s| {
+        s.iter()
+            .find(|m| m.guild_id == guild_id && m.user_id == user_id)
+            .map(|m| m.roles.clone())
+    });
+    if recorded.unwrap_or_default() == tracked {
+        return;
+    }
+    CURRENT_TRACKED_ROLES.update(|s| {
+        s.retain(|m| !(m.guild_id == guild_id && m.user_id == user_id));
+        if !tracked.is_empty() {
+            s.push(CurrentMemberRoles {
+                guild_id,
+                user_id,
+                roles: tracked,
+            });
+        }
+    });
+}
+
+/// Records the tracked roles of every member, for those who joined while the bot was offline.
+async fn seed_tracked_roles(
+    ctx: &serenity::Context,
+    config: &StickyRoleConfig,
+    guild_id: GuildId,
+) -> Result<(), HandlerError> {
+    let mut recorded = vec![];
+    let mut members = guild_id.members_iter(ctx).boxed();
+    while let Some(member) = members.next().await {
+        let member = member?;
+        let roles = config.tracked(&member.roles);
+        if !roles.is_empty() {
+            recorded.push(CurrentMemberRoles {
+                guild_id,
+                user_id: member.user.id,
+                roles,
+            });
+        }
+    }
+    CURRENT_TRACKED_ROLES.update(|s| {
+        s.retain(|m| m.guild_id != guild_id);
+        s.extend(recorded);
+    });
+    Ok(())
+}
+
+/// The channel translation rules apply to for `channel_id`, and its category. Threads count as
+/// their parent channel.
+async fn channel_location(
+    ctx: &serenity::Context,
+    channel_id: ChannelId,
+) -> Result<(ChannelId, Option<ChannelId>), HandlerError> {
+    let Some(channel) = channel_id.to_channel(ctx).await?.guild() else {
+        return Ok((channel_id, None));
+    };
+    if channel.thread_metadata.is_some()
+        && let Some(parent) = channel.parent_id
+    {
+        let category = parent
+            .to_channel(ctx)
+            .await?
+            .guild()
+            .and_then(|c| c.parent_id);
+        return Ok((parent, category));
+    }
+    Ok((channel.id, channel.parent_id))
+}
+
+/// The country of a flag reaction, if it is one.
+fn reaction_flag(reaction: &Reaction) -> Option<String> {
+    match &reaction.emoji {
+        ReactionType::Unicode(code) => flag_country(code),
+        _ => None,
+    }
+}
+
+async fn on_reaction_add(ctx: &serenity::Context, reaction: &Reaction) -> Result<(), HandlerError> {
+    if let Some(role_id) = bound_role(reaction) {
+        if let (Some(guild_id), Some(user_id)) = (reaction.guild_id, reaction.user_id)
+            && user_id != ctx.cache.current_user().id
         {
-            mem.remove_role(&ctx, sel_role).await.unwrap();
-            "You removed"
-        } else {
-            mem.add_role(&ctx, sel_role).await.unwrap();
-            "You added"
+            ctx.http
+                .add_member_role(guild_id, user_id, role_id, Some("Reaction role"))
+                .await?;
+        }
+        return Ok(());
+    }
+
+    let Some(country) = reaction_flag(reaction) else {
+        return Ok(());
+    };
+    let rules = &TRANSLATE_CONFIG.reactions;
+    let roles = reaction.member.as_ref().map_or(&[][..], |m| &m.roles);
+    if !rules.allows_member(roles) {
+        return Ok(());
+    }
+    let (channel, category) = channel_location(ctx, reaction.channel_id).await?;
+    if !rules.allows_channel(channel, category) {
+        return Ok(());
+    }
+    let m = reaction.message(ctx).await?;
+    if TRANSLATE_OPTOUTS.read(|s| s.contains(&m.author.id)) {
+        return Ok(());
+    }
+    match flag_language(&country, &TRANSLATE_CONFIG.flags) {
+        Some(lang) => translate_reaction(ctx, reaction, &m, lang).await,
+        None => {
+            {
+                let mut notices = FLAG_NOTICES.lock().unwrap();
+                if notices.get(&m.id).is_some()
+                    || !within_translate_limits(reaction.user_id, reaction.guild_id)
+                {
+                    return Ok(());
+                }
+                notices.insert(m.id, ());
+            }
+            let names = LANGUAGES
+                .iter()
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>()
+                .join(", ");
+            let notice = m
+                .reply(
+                    ctx,
+                    format!(
+                        "I don't know which language {} stands for. Try the flag of a country that speaks one of: {names}",
+                        reaction.emoji
+                    ),
+                )
+                .await?;
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_secs(15)).await;
+                if let Err(e) = notice.delete(&ctx).await {
+                    eprintln!("Failed to delete flag notice: {e}");
+                }
+            });
+            Ok(())
+        }
+    }
+}
+
+async fn on_reaction_remove(
+    ctx: &serenity::Context,
+    reaction: &Reaction,
+) -> Result<(), HandlerError> {
+    if let Some(role_id) = bound_role(reaction) {
+        if let (Some(guild_id), Some(user_id)) = (reaction.guild_id, reaction.user_id)
+            && user_id != ctx.cache.current_user().id
+        {
+            ctx.http
+                .remove_member_role(guild_id, user_id, role_id, Some("Reaction role"))
+                .await?;
+        }
+        return Ok(());
+    }
+
+    if TRANSLATE_CONFIG.reactions.remove_on_unreact
+        && let Some(country) = reaction_flag(reaction)
+        && let Some(lang) = flag_language(&country, &TRANSLATE_CONFIG.flags)
+        && let Some(user_id) = reaction.user_id
+    {
+        withdraw_translation(ctx, reaction.message_id, user_id, lang).await?;
+    }
+    Ok(())
+}
+
+async fn on_component(
+    ctx: &serenity::Context,
+    interaction: &ComponentInteraction,
+) -> Result<(), HandlerError> {
+    let mem = interaction
+        .member
+        .as_ref()
+        .ok_or(HandlerError::Missing("member"))?;
+    let (choice, change) = match route(
+        &ROLE_CONFIG,
+        &interaction.data.custom_id,
+        &interaction.data.kind,
+    ) {
+        Component::ModmailClose => return close_ticket(ctx, interaction).await,
+        Component::RoleButton { choice, role_id } => {
+            (choice, toggle_role(choice, &mem.roles, role_id))
+        }
+        Component::RoleSelect { choice, selected } => {
+            (choice, select_roles(choice, &mem.roles, &selected))
+        }
+        Component::Collector => return Ok(()),
+        Component::Unknown => {
+            outdated_component(ctx, interaction).await;
+            return Ok(());
+        }
+    };
+
+    let reply = match change {
+        Ok(change) => {
+            if !change.remove.is_empty() {
+                mem.remove_roles(ctx, &change.remove).await?;
+            }
+            if !change.add.is_empty() {
+                mem.add_roles(ctx, &change.add).await?;
+            }
+            let now = chrono::Utc::now();
+            let expiries = change
+                .add
+                .iter()
+                .filter_map(|r| {
+                    choice.duration_of(*r).map(|d| RoleExpiry {
+                        guild_id: mem.guild_id,
+                        user_id: mem.user.id,
+                        role_id: *r,
+                        expires_at: (now + d).timestamp(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            ROLE_EXPIRIES.update(|s| {
+                s.retain(|e| {
+                    !(e.user_id == mem.user.id
+                        && e.guild_id == mem.guild_id
+                        && (change.add.contains(&e.role_id) || change.remove.contains(&e.role_id)))
+                });
+                s.extend(expiries.iter().cloned());
+            });
+
+            let at = now.timestamp();
+            let entries = change
+                .add
+                .iter()
+                .map(|r| (r, true))
+                .chain(change.remove.iter().map(|r| (r, false)))
+                .map(|(r, added)| RoleLogEntry {
+                    guild_id: mem.guild_id,
+                    user_id: mem.user.id,
+                    role_id: *r,
+                    added,
+                    at,
+                })
+                .collect::<Vec<_>>();
+            let cutoff = (now - chrono::Duration::days(60)).timestamp();
+            ROLE_HISTORY.update(|s| {
+                s.retain(|e| e.at > cutoff);
+                s.extend(entries.iter().cloned());
+            });
+            // Only the log channel flush drains this, so don't queue without one
+            if ROLE_CONFIG.log_channel.is_some() {
+                PENDING_ROLE_LOG.lock().unwrap().extend(entries);
+            }
+
+            let mut reply = describe_change(&change);
+            for e in &expiries {
+                reply.push_str(&format!(
+                    "\n<@&{}> expires <t:{}:R>",
+                    e.role_id, e.expires_at
+                ));
+            }
+            reply
+        }
+        Err(why) => why,
+    };
+
+    interaction
+        .create_response(
+            ctx,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::default()
+                    .ephemeral(true)
+                    .content(reply),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn message(&self, ctx: serenity::Context, new_message: Message) {
+        // Our own translations come from the bot or its webhooks, never translate them again
+        if new_message.author.bot || new_message.webhook_id.is_some() {
+            return;
+        }
+        if let Some(auto) = TRANSLATE_CONFIG
+            .auto
+            .iter()
+            .find(|a| a.channel_id == new_message.channel_id)
+        {
+            let result = auto_translate(&ctx, auto, &new_message).await;
+            report(&ctx, "message", result).await;
+        }
+        let is_staff = new_message
+            .member
+            .as_ref()
+            .is_some_and(|m| m.roles.contains(&MOD_MAIL_CONFIG.mod_role));
+        if is_staff {
+            let needs_update = TICKETS.read(|s| {
+                s.by_channel(new_message.channel_id)
+                    .is_some_and(|t| t.awaits_response_from(new_message.author.id))
+            });
+            if needs_update {
+                TICKETS.update(|s| {
+                    if let Some(t) = s.by_channel_mut(new_message.channel_id) {
+                        t.record_response(
+                            new_message.author.id,
+                            new_message.timestamp.unix_timestamp(),
+                        );
+                    }
+                });
+            }
+        }
+    }
+
+    async fn guild_member_removal(
+        &self,
+        _ctx: serenity::Context,
+        guild_id: GuildId,
+        user: User,
+        member_data_if_available: Option<Member>,
+    ) {
+        let Some(config) = &*STICKY_ROLE_CONFIG else {
+            return;
+        };
+        // Large guilds don't cache every member, so fall back to the roles kept on record
+        let recorded = CURRENT_TRACKED_ROLES.read(|s| {
+            s.iter()
+                .find(|m| m.guild_id == guild_id && m.user_id == user.id)
+                .map(|m| m.roles.clone())
+        });
+        let roles = match member_data_if_available {
+            Some(member) => config.tracked(&member.roles),
+            None => recorded.clone().unwrap_or_default(),
         };
+        if recorded.is_some() {
+            CURRENT_TRACKED_ROLES
+                .update(|s| s.retain(|m| !(m.guild_id == guild_id && m.user_id == user.id)));
+        }
+        if roles.is_empty() {
+            return;
+        }
 
-        interaction
-            .create_response(
-                &ctx,
-                CreateInteractionResponse::Message(
-                    CreateInteractionResponseMessage::default()
-                        .ephemeral(true)
-                        .content(format!("{} {}", reply, sel_role.name)),
-                ),
-            )
-            .await
-            .unwrap();
+        DEPARTED_MEMBER_ROLES.update(|s| {
+            s.retain(|m| !(m.guild_id == guild_id && m.user_id == user.id));
+            s.push(DepartedMemberRoles {
+                guild_id,
+                user_id: user.id,
+                roles,
+                left_at: chrono::Utc::now().timestamp(),
+            });
+        });
+    }
+
+    async fn guild_member_update(
+        &self,
+        _ctx: serenity::Context,
+        _old_if_available: Option<Member>,
+        _new: Option<Member>,
+        event: GuildMemberUpdateEvent,
+    ) {
+        if let Some(config) = &*STICKY_ROLE_CONFIG {
+            record_tracked_roles(config, event.guild_id, event.user.id, &event.roles);
+        }
+    }
+
+    async fn guild_member_addition(&self, ctx: serenity::Context, new_member: Member) {
+        let Some(config) = &*STICKY_ROLE_CONFIG else {
+            return;
+        };
+        let snapshot = DEPARTED_MEMBER_ROLES.read(|s| {
+            s.iter()
+                .find(|m| m.guild_id == new_member.guild_id && m.user_id == new_member.user.id)
+                .cloned()
+        });
+        let Some(snapshot) = snapshot else {
+            return;
+        };
+
+        let roles = config.roles_to_restore(&snapshot, chrono::Utc::now().timestamp());
+        if !roles.is_empty()
+            && let Err(e) = new_member.add_roles(&ctx, &roles).await
+        {
+            report(&ctx, "guild_member_addition", Err(e.into())).await;
+            return;
+        }
+        DEPARTED_MEMBER_ROLES.update(|s| {
+            s.retain(|m| !(m.guild_id == new_member.guild_id && m.user_id == new_member.user.id))
+        });
+    }
+
+    async fn reaction_add(&self, ctx: serenity::Context, add_reaction: Reaction) {
+        let result = on_reaction_add(&ctx, &add_reaction).await;
+        report(&ctx, "reaction_add", result).await;
+    }
+
+    async fn reaction_remove(&self, ctx: serenity::Context, removed_reaction: Reaction) {
+        let result = on_reaction_remove(&ctx, &removed_reaction).await;
+        report(&ctx, "reaction_remove", result).await;
+    }
+
+    async fn interaction_create(&self, ctx: serenity::Context, interaction: Interaction) {
+        let interaction = match interaction {
+            Interaction::Component(i) => i,
+            _ => {
+                eprintln!("Unimplemented interaction: {interaction:?}");
+                return;
+            }
+        };
+        let result = on_component(&ctx, &interaction).await;
+        report(&ctx, "interaction_create", result).await;
     }
 
     async fn ready(&self, ctx: serenity::Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
-        let _ = &*ROLE_MAP;
+        let _ = &*ROLE_CONFIG;
         let ctx2 = ctx.clone();
+        let ctx3 = ctx.clone();
 
         tokio::spawn(async move {
             let config_file = fs::read_to_string("verification.toml").unwrap();
@@ -161,38 +1199,12 @@ impl EventHandler for Handler {
             let verification_period: i64 =
                 chrono::Duration::days(3).to_std().unwrap().as_secs() as i64;
 
-            let now = chrono::Utc::now();
-            let mut start = now
-                .date_naive()
-                .and_hms_opt(0, 0, 0)
-                .unwrap()
-                .signed_duration_since(now.naive_utc());
-            let period = chrono::Duration::hours(24).to_std().unwrap();
-
-            if start < chrono::Duration::zero() {
-                start = start.checked_add(&chrono::Duration::hours(24)).unwrap();
-            }
-
-            let mut interval = tokio::time::interval_at(
-                tokio::time::Instant::now() + start.to_std().unwrap(),
-                period,
-            );
+            let mut interval = daily_interval(0, 0, 0);
 
             loop {
-                let now_timestamp = chrono::Utc::now().timestamp();
-                let all_members = guild_id.members(&ctx2, None, None).await.unwrap();
-                let unverified_members = all_members
-                    .iter()
-                    .filter(|m| !m.roles.contains(&verified_role_id));
-                for m in unverified_members {
-                    let join_time = m.joined_at;
-                    if let Some(joined) = join_time
-                        && now_timestamp - joined.timestamp() > verification_period
-                    {
-                        println!("{} has been verified", m.display_name());
-                        m.add_roles(&ctx2, &[verified_role_id]).await.unwrap();
-                    }
-                }
+                let result =
+                    verify_members(&ctx2, guild_id, verified_role_id, verification_period).await;
+                report(&ctx2, "verification", result).await;
 
                 interval.tick().await;
             }
@@ -203,55 +1215,133 @@ impl EventHandler for Handler {
             let config = PurgeTimerConfig::from_config(config.as_str());
             let purge_time = config.time.time.unwrap();
 
-            let now = chrono::Utc::now();
-            let mut start = now
-                .date_naive()
-                .and_hms_opt(
-                    purge_time.hour.into(),
-                    purge_time.minute.into(),
-                    purge_time.second.into(),
-                )
-                .unwrap()
-                .signed_duration_since(now.naive_utc());
-            let period = chrono::Duration::hours(24).to_std().unwrap();
+            let mut interval =
+                daily_interval(purge_time.hour, purge_time.minute, purge_time.second);
 
-            if start < chrono::Duration::zero() {
-                start = start.checked_add(&chrono::Duration::hours(24)).unwrap();
-            }
+            loop {
+                interval.tick().await;
 
-            let mut interval = tokio::time::interval_at(
-                tokio::time::Instant::now() + start.to_std().unwrap(),
-                period,
-            );
+                let result = purge_channel(&ctx, config.channel_id).await;
+                report(&ctx, "purge", result).await;
+            }
+        });
 
+        let ctx4 = ctx3.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
             loop {
                 interval.tick().await;
+                expire_roles(&ctx4).await;
+            }
+        });
 
-                delete_all_messages_except_mine(&ctx, &config.channel_id).await;
-
-                let next_purge = chrono::Utc::now()
-                    .checked_add_days(chrono::Days::new(1))
-                    .unwrap()
-                    .timestamp();
-
-                config
-                    .channel_id
-                    .messages_iter(&ctx)
-                    .boxed()
-                    .next()
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .edit(
-                        &ctx,
-                        EditMessage::new()
-                            .content(format!("Channel will be purged in <t:{next_purge}:R>")),
-                    )
-                    .await
-                    .unwrap();
+        let guilds = ready.guilds.iter().map(|g| g.id).collect::<Vec<_>>();
+        if let Some(config) = &*STICKY_ROLE_CONFIG {
+            let ctx = ctx3.clone();
+            let guilds = guilds.clone();
+            tokio::spawn(async move {
+                for guild_id in guilds {
+                    let result = seed_tracked_roles(&ctx, config, guild_id).await;
+                    report(&ctx, "tracked role seeding", result).await;
+                }
+            });
+        }
+
+        let ctx = ctx3.clone();
+        tokio::spawn(async move {
+            let mut interval = daily_interval(0, 0, 0);
+            loop {
+                interval.tick().await;
+                for guild_id in &guilds {
+                    snapshot_roles(&ctx, *guild_id).await;
+                }
             }
         });
 
+        if let Some(channel_id) = ROLE_CONFIG.log_channel {
+            let ctx = ctx3.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+                loop {
+                    interval.tick().await;
+                    flush_role_log(&ctx, channel_id).await;
+                }
+            });
+        }
+
+        if let Some(after_minutes) = MOD_MAIL_CONFIG.notify.escalate_after_minutes {
+            let ctx = ctx3.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+                loop {
+                    interval.tick().await;
+                    escalate_tickets(&ctx, after_minutes).await;
+                }
+            });
+        }
+
+        if let (Some(channel_id), Some(time)) = (
+            MOD_MAIL_CONFIG.notify.alert_channel,
+            MOD_MAIL_CONFIG.notify.digest_time.and_then(|t| t.time),
+        ) {
+            tokio::spawn(async move {
+                let mut interval = daily_interval(time.hour, time.minute, time.second);
+                loop {
+                    interval.tick().await;
+                    post_ticket_digest(&ctx3, channel_id).await;
+                }
+            });
+        }
+
         println!("{} has setup!", ready.user.name);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reaction(emoji: serde_json::Value) -> Reaction {
+        serde_json::from_value(serde_json::json!({
+            "channel_id": "1",
+            "message_id": "2",
+            "user_id": "3",
+            "guild_id": "4",
+            "emoji": emoji,
+            "burst": false,
+            "type": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reaction_flag_test() {
+        assert_eq!(
+            reaction_flag(&reaction(serde_json::json!({ "name": "🇸🇪" }))),
+            Some("SE".to_string())
+        );
+        // A lone regional indicator, as sent when a flag is half typed
+        assert_eq!(
+            reaction_flag(&reaction(serde_json::json!({ "name": "🇸" }))),
+            None
+        );
+        assert_eq!(
+            reaction_flag(&reaction(serde_json::json!({ "name": "🇸🇪🇸" }))),
+            None
+        );
+        assert_eq!(
+            reaction_flag(&reaction(serde_json::json!({ "name": "👍" }))),
+            None
+        );
+        assert_eq!(
+            reaction_flag(&reaction(
+                serde_json::json!({ "id": "5", "name": "se", "animated": false })
+            )),
+            None
+        );
+        assert_eq!(
+            bound_role(&reaction(serde_json::json!({ "name": "🇸" }))),
+            None
+        );
+    }
+}
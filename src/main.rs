@@ -1,16 +1,31 @@
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
 use std::{env, fs};
 mod commands;
+mod errors;
 mod handler;
 mod read_conf;
+mod roles;
+mod router;
+mod store;
+mod tickets;
+mod translate;
 
-use commands::{initrolechannel, modmail, modmail_admin, register};
+use commands::{
+    detect, initrolechannel, modmail, modmail_admin, reactionrole, register, reply, roles, snippet,
+    ticket, translate, translate_context_menu,
+};
 use dotenv::dotenv;
 use handler::Handler;
 use indexmap::IndexMap;
 use poise::serenity_prelude as serenity;
-use read_conf::{ModMailConfig, RoleConfig};
+use read_conf::{ErrorConfig, ModMailConfig, RoleConfig, StickyRoleConfig, TranslateConfig};
 use reqwest::Client as HttpClient;
+use roles::{
+    CurrentMemberRoles, DepartedMemberRoles, ReactionRole, RoleExpiry, RoleLogEntry, RoleSnapshot,
+};
+use store::Store;
+use tickets::TicketStore;
+use translate::{Translator, build_translator};
 
 use ::serenity::prelude::TypeMapKey;
 use serenity::model::prelude::*;
@@ -26,6 +41,12 @@ impl TypeMapKey for HttpKey {
     type Value = HttpClient;
 }
 
+struct TranslatorKey;
+
+impl TypeMapKey for TranslatorKey {
+    type Value = Arc<dyn Translator>;
+}
+
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
@@ -36,18 +57,51 @@ static MOD_MAIL_CONFIG: LazyLock<ModMailConfig> = LazyLock::new(|| {
 static ROLE_CONFIG: LazyLock<RoleConfig> =
     LazyLock::new(|| RoleConfig::from_config(fs::read_to_string("roles.toml").unwrap().as_str()));
 
-static ROLE_MAP: LazyLock<IndexMap<String, RoleId>> = LazyLock::new(|| {
-    IndexMap::from_iter(
-        (ROLE_CONFIG)
-            .choices
-            .iter()
-            .map(|c| &(c.options))
-            .flat_map(|c| {
-                c.iter()
-                    .map(|(b_id, b)| (b_id.clone(), b.role_id))
-                    .collect::<Vec<(String, RoleId)>>()
-            }),
-    )
+static TICKETS: LazyLock<Store<TicketStore>> = LazyLock::new(|| Store::load("tickets.json"));
+
+static SNIPPETS: LazyLock<Store<IndexMap<String, String>>> =
+    LazyLock::new(|| Store::load("snippets.json"));
+
+static ROLE_MENUS: LazyLock<Store<IndexMap<ChannelId, IndexMap<String, MessageId>>>> =
+    LazyLock::new(|| Store::load("role_menus.json"));
+
+static REACTION_ROLES: LazyLock<Store<Vec<ReactionRole>>> =
+    LazyLock::new(|| Store::load("reaction_roles.json"));
+
+static ROLE_EXPIRIES: LazyLock<Store<Vec<RoleExpiry>>> =
+    LazyLock::new(|| Store::load("role_expiries.json"));
+
+static ROLE_HISTORY: LazyLock<Store<Vec<RoleLogEntry>>> =
+    LazyLock::new(|| Store::load("role_history.json"));
+
+static ROLE_SNAPSHOTS: LazyLock<Store<Vec<RoleSnapshot>>> =
+    LazyLock::new(|| Store::load("role_snapshots.json"));
+
+static STICKY_ROLE_CONFIG: LazyLock<Option<StickyRoleConfig>> = LazyLock::new(|| {
+    fs::read_to_string("sticky.toml")
+        .ok()
+        .map(|c| StickyRoleConfig::from_config(c.as_str()))
+});
+
+static TRANSLATE_OPTOUTS: LazyLock<Store<Vec<UserId>>> =
+    LazyLock::new(|| Store::load("translate_optouts.json"));
+
+static ERROR_CONFIG: LazyLock<Option<ErrorConfig>> = LazyLock::new(|| {
+    fs::read_to_string("errors.toml")
+        .ok()
+        .map(|c| ErrorConfig::from_config(c.as_str()))
+});
+
+static CURRENT_TRACKED_ROLES: LazyLock<Store<Vec<CurrentMemberRoles>>> =
+    LazyLock::new(|| Store::load("current_roles.json"));
+
+static DEPARTED_MEMBER_ROLES: LazyLock<Store<Vec<DepartedMemberRoles>>> =
+    LazyLock::new(|| Store::load("departed_roles.json"));
+
+static TRANSLATE_CONFIG: LazyLock<TranslateConfig> = LazyLock::new(|| {
+    fs::read_to_string("translate.toml")
+        .map(|c| TranslateConfig::from_config(c.as_str()))
+        .unwrap_or_default()
 });
 
 #[tokio::main]
@@ -71,6 +125,14 @@ async fn main() {
                 join_vc(),
                 leave_vc(),
                 play_yt(),
+                snippet(),
+                reply(),
+                ticket(),
+                reactionrole(),
+                roles(),
+                translate(),
+                translate_context_menu(),
+                detect(),
             ],
             ..Default::default()
         })
@@ -82,11 +144,15 @@ async fn main() {
         })
         .build();
 
+    let http_client = HttpClient::new();
+    let translator = build_translator(&TRANSLATE_CONFIG.backend, http_client.clone());
+
     let mut client = serenity::Client::builder(&token, intents)
         .event_handler(Handler)
         .framework(framework)
         .register_songbird()
-        .type_map_insert::<HttpKey>(HttpClient::new())
+        .type_map_insert::<HttpKey>(http_client)
+        .type_map_insert::<TranslatorKey>(translator)
         .await
         .expect("Err creating client");
 
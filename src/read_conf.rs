@@ -1,23 +1,88 @@
 use indexmap::IndexMap;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use serenity::all::{ChannelId, GuildId, RoleId};
 
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct RoleConfig {
+    pub admin_channel: Option<ChannelId>,
+    pub log_channel: Option<ChannelId>,
     pub choices: Vec<RoleChoice>,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct RoleChoice {
+    /// Identifies the menu when choices are added, removed or reordered. Defaults to `message`.
+    pub id: Option<String>,
     pub message: String,
+    #[serde(default)]
+    pub mode: ChoiceMode,
+    #[serde(default)]
+    pub style: ChoiceStyle,
+    pub min: Option<usize>,
+    pub max: Option<usize>,
     pub options: IndexMap<String, RoleButton>,
 }
 
+#[derive(Deserialize, Debug, PartialEq, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ChoiceMode {
+    Exclusive,
+    #[default]
+    Multiple,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ChoiceStyle {
+    #[default]
+    Buttons,
+    Select,
+}
+
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct RoleButton {
     pub emoji: String,
     pub label: String,
+    pub description: Option<String>,
     pub role_id: RoleId,
+    #[serde(default)]
+    pub requires: Vec<RoleId>,
+    #[serde(default)]
+    pub conflicts: Vec<RoleId>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub duration: Option<chrono::Duration>,
+}
+
+/// Parses durations such as `"30m"`, `"7d"` or `"1w2d"`.
+pub fn parse_duration(s: &str) -> Option<chrono::Duration> {
+    let mut total = chrono::Duration::zero();
+    let mut number = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: i64 = number.parse().ok()?;
+        number.clear();
+        total += match c {
+            's' => chrono::Duration::seconds(n),
+            'm' => chrono::Duration::minutes(n),
+            'h' => chrono::Duration::hours(n),
+            'd' => chrono::Duration::days(n),
+            'w' => chrono::Duration::weeks(n),
+            _ => return None,
+        };
+    }
+    (number.is_empty() && total > chrono::Duration::zero()).then_some(total)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<chrono::Duration>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("{s} is not a valid duration")))
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
@@ -30,6 +95,196 @@ pub struct PurgeTimerConfig {
 pub struct ModMailConfig {
     pub channel_id: ChannelId,
     pub mod_role: RoleId,
+    #[serde(default)]
+    pub notify: ModMailNotifyConfig,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Default)]
+pub struct ModMailNotifyConfig {
+    #[serde(default)]
+    pub ping_mod_role: bool,
+    pub alert_channel: Option<ChannelId>,
+    pub escalate_after_minutes: Option<i64>,
+    pub digest_time: Option<toml::value::Datetime>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct StickyRoleConfig {
+    #[serde(default)]
+    pub restore: Vec<RoleId>,
+    #[serde(default)]
+    pub sticky: Vec<RoleId>,
+    pub restore_within_days: Option<i64>,
+}
+
+/// Where failures in the event handler are reported, besides the log.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ErrorConfig {
+    pub channel_id: ChannelId,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct TranslateConfig {
+    #[serde(default)]
+    pub backend: BackendConfig,
+    #[serde(default)]
+    pub flags: IndexMap<String, String>,
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+    #[serde(default)]
+    pub rate_limit: TranslateRateLimit,
+    #[serde(default)]
+    pub auto: Vec<AutoTranslateChannel>,
+    #[serde(default)]
+    pub reactions: ReactionTranslateConfig,
+}
+
+/// Where flag reactions translate messages, and who may use them. Deny lists win over allow
+/// lists, and empty allow lists allow everywhere.
+#[derive(Deserialize, Debug, PartialEq, Default)]
+pub struct ReactionTranslateConfig {
+    #[serde(default)]
+    pub allow_channels: Vec<ChannelId>,
+    #[serde(default)]
+    pub deny_channels: Vec<ChannelId>,
+    #[serde(default)]
+    pub allow_categories: Vec<ChannelId>,
+    #[serde(default)]
+    pub deny_categories: Vec<ChannelId>,
+    pub required_role: Option<RoleId>,
+    #[serde(default)]
+    pub delivery: ReactionDelivery,
+    /// Takes a translation down again once everyone who asked for it unreacts.
+    #[serde(default)]
+    pub remove_on_unreact: bool,
+}
+
+/// How translations asked for with a flag reaction are delivered.
+#[derive(Deserialize, Debug, PartialEq, Default, Clone, Copy)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum ReactionDelivery {
+    /// A reply to the message.
+    #[default]
+    Reply,
+    /// A reply to the message, deleted after some minutes.
+    Expiring {
+        #[serde(default = "default_expire_minutes")]
+        minutes: u64,
+    },
+    /// A message in a thread started from the message.
+    Thread,
+    /// A direct message to whoever reacted.
+    Dm,
+}
+
+fn default_expire_minutes() -> u64 {
+    5
+}
+
+/// A channel where messages in one of `languages` are translated into the other.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct AutoTranslateChannel {
+    pub channel_id: ChannelId,
+    pub languages: [String; 2],
+    #[serde(default)]
+    pub output: AutoTranslateOutput,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoTranslateOutput {
+    /// Reposted under the author's name and avatar.
+    #[default]
+    Webhook,
+    /// Posted in a thread started from the original message.
+    Thread,
+}
+
+/// How many translations may be requested per minute before reactions are ignored.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct TranslateRateLimit {
+    #[serde(default = "default_user_limit")]
+    pub user_per_minute: usize,
+    #[serde(default = "default_guild_limit")]
+    pub guild_per_minute: usize,
+    /// Messages `/detect stats` may sample per guild, kept apart from the translation budget.
+    #[serde(default = "default_sample_limit")]
+    pub sample_per_minute: usize,
+}
+
+impl Default for TranslateRateLimit {
+    fn default() -> Self {
+        TranslateRateLimit {
+            user_per_minute: default_user_limit(),
+            guild_per_minute: default_guild_limit(),
+            sample_per_minute: default_sample_limit(),
+        }
+    }
+}
+
+impl Default for TranslateConfig {
+    fn default() -> Self {
+        TranslateConfig {
+            backend: BackendConfig::default(),
+            flags: IndexMap::new(),
+            cache_size: default_cache_size(),
+            rate_limit: TranslateRateLimit::default(),
+            auto: vec![],
+            reactions: ReactionTranslateConfig::default(),
+        }
+    }
+}
+
+fn default_cache_size() -> usize {
+    500
+}
+
+fn default_user_limit() -> usize {
+    5
+}
+
+fn default_guild_limit() -> usize {
+    30
+}
+
+fn default_sample_limit() -> usize {
+    100
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BackendConfig {
+    /// Falls back to the `TRANSLATE_URL` environment variable when `url` is not set.
+    LibreTranslate {
+        url: Option<String>,
+        api_key: Option<String>,
+    },
+    DeepL {
+        #[serde(default = "default_deepl_url")]
+        url: String,
+        auth_key: String,
+    },
+    Template {
+        url: String,
+        body: String,
+        #[serde(default)]
+        headers: IndexMap<String, String>,
+        text_pointer: String,
+        source_pointer: Option<String>,
+    },
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig::LibreTranslate {
+            url: None,
+            api_key: None,
+        }
+    }
+}
+
+fn default_deepl_url() -> String {
+    String::from("https://api-free.deepl.com/v2/translate")
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
@@ -44,6 +299,24 @@ impl VerificationConfig {
     }
 }
 
+impl StickyRoleConfig {
+    pub fn from_config(config: &str) -> Self {
+        toml::from_str(config).unwrap()
+    }
+}
+
+impl ErrorConfig {
+    pub fn from_config(config: &str) -> Self {
+        toml::from_str(config).unwrap()
+    }
+}
+
+impl TranslateConfig {
+    pub fn from_config(config: &str) -> Self {
+        toml::from_str(config).unwrap()
+    }
+}
+
 impl RoleConfig {
     pub fn from_config(config: &str) -> Self {
         toml::from_str(config).unwrap()
@@ -84,16 +357,27 @@ message = \"Choose type 2\"
         assert_eq!(
             RoleConfig::from_config(config),
             RoleConfig {
+                admin_channel: None,
+                log_channel: None,
                 choices: vec![
                     RoleChoice {
+                        id: None,
                         message: String::from("Choose type 1"),
+                        mode: ChoiceMode::Multiple,
+                        style: ChoiceStyle::Buttons,
+                        min: None,
+                        max: None,
                         options: IndexMap::from([
                             (
                                 String::from("t1_one"),
                                 RoleButton {
                                     emoji: String::from("emoji_1"),
                                     label: String::from("label_1"),
+                                    description: None,
                                     role_id: RoleId::from(1),
+                                    requires: vec![],
+                                    conflicts: vec![],
+                                    duration: None,
                                 }
                             ),
                             (
@@ -101,19 +385,32 @@ message = \"Choose type 2\"
                                 RoleButton {
                                     emoji: String::from("emoji_2"),
                                     label: String::from("label_2"),
+                                    description: None,
                                     role_id: RoleId::from(2),
+                                    requires: vec![],
+                                    conflicts: vec![],
+                                    duration: None,
                                 }
                             )
                         ]),
                     },
                     RoleChoice {
+                        id: None,
                         message: String::from("Choose type 2"),
+                        mode: ChoiceMode::Multiple,
+                        style: ChoiceStyle::Buttons,
+                        min: None,
+                        max: None,
                         options: IndexMap::from([(
                             String::from("t2_one"),
                             RoleButton {
                                 emoji: String::from("emoki_1"),
                                 label: String::from("label_3"),
+                                description: None,
                                 role_id: RoleId::from(3),
+                                requires: vec![],
+                                conflicts: vec![],
+                                duration: None,
                             }
                         ),]),
                     }
@@ -121,4 +418,170 @@ message = \"Choose type 2\"
             }
         )
     }
+
+    #[test]
+    fn role_choice_test() {
+        let config = "
+[[choices]]
+id = \"colour\"
+message = \"Pick a colour\"
+mode = \"exclusive\"
+style = \"select\"
+min = 1
+max = 1
+[choices.options]
+    red = { emoji = \"r\", label = \"Red\", description = \"Warm\", role_id = 1, requires = [2], conflicts = [3] }
+";
+
+        let choice = &RoleConfig::from_config(config).choices[0];
+        assert_eq!(choice.id.as_deref(), Some("colour"));
+        assert_eq!(choice.mode, ChoiceMode::Exclusive);
+        assert_eq!(choice.style, ChoiceStyle::Select);
+        assert_eq!((choice.min, choice.max), (Some(1), Some(1)));
+        let red = &choice.options["red"];
+        assert_eq!(red.description.as_deref(), Some("Warm"));
+        assert_eq!(red.requires, vec![RoleId::new(2)]);
+        assert_eq!(red.conflicts, vec![RoleId::new(3)]);
+    }
+
+    #[test]
+    fn duration_test() {
+        assert_eq!(parse_duration("7d"), Some(chrono::Duration::days(7)));
+        assert_eq!(
+            parse_duration("1w2h"),
+            Some(chrono::Duration::weeks(1) + chrono::Duration::hours(2))
+        );
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("3y"), None);
+        assert_eq!(parse_duration("0m"), None);
+
+        let button: RoleButton =
+            toml::from_str("emoji = \"e\"\nlabel = \"l\"\nrole_id = 1\nduration = \"30m\"")
+                .unwrap();
+        assert_eq!(button.duration, Some(chrono::Duration::minutes(30)));
+    }
+
+    #[test]
+    fn translate_test() {
+        assert_eq!(TranslateConfig::from_config(""), TranslateConfig::default());
+
+        let config = TranslateConfig::from_config(
+            "
+[backend]
+kind = \"deepl\"
+auth_key = \"key\"
+
+[flags]
+CA = \"fr\"
+",
+        );
+        assert_eq!(
+            config.backend,
+            BackendConfig::DeepL {
+                url: default_deepl_url(),
+                auth_key: String::from("key"),
+            }
+        );
+        assert_eq!(config.flags.get("CA").map(String::as_str), Some("fr"));
+        assert_eq!(config.rate_limit, TranslateRateLimit::default());
+
+        let config = TranslateConfig::from_config("rate_limit = { user_per_minute = 2 }");
+        assert_eq!(config.rate_limit.user_per_minute, 2);
+        assert_eq!(config.rate_limit.guild_per_minute, default_guild_limit());
+        assert_eq!(config.rate_limit.sample_per_minute, default_sample_limit());
+
+        let config = TranslateConfig::from_config(
+            "
+[[auto]]
+channel_id = 1
+languages = [\"en\", \"sv\"]
+
+[[auto]]
+channel_id = 2
+languages = [\"de\", \"fr\"]
+output = \"thread\"
+",
+        );
+        assert_eq!(
+            config.auto[0],
+            AutoTranslateChannel {
+                channel_id: ChannelId::new(1),
+                languages: [String::from("en"), String::from("sv")],
+                output: AutoTranslateOutput::Webhook,
+            }
+        );
+        assert_eq!(config.auto[1].output, AutoTranslateOutput::Thread);
+
+        let config = TranslateConfig::from_config(
+            "
+[reactions]
+deny_channels = [1]
+allow_categories = [2]
+required_role = 3
+",
+        );
+        assert_eq!(
+            config.reactions,
+            ReactionTranslateConfig {
+                deny_channels: vec![ChannelId::new(1)],
+                allow_categories: vec![ChannelId::new(2)],
+                required_role: Some(RoleId::new(3)),
+                ..Default::default()
+            }
+        );
+
+        let config = TranslateConfig::from_config(
+            "
+[reactions]
+remove_on_unreact = true
+delivery = { mode = \"expiring\" }
+",
+        );
+        assert_eq!(
+            config.reactions.delivery,
+            ReactionDelivery::Expiring { minutes: 5 }
+        );
+        assert!(config.reactions.remove_on_unreact);
+        assert_eq!(
+            TranslateConfig::from_config("reactions.delivery.mode = \"dm\"")
+                .reactions
+                .delivery,
+            ReactionDelivery::Dm
+        );
+    }
+
+    #[test]
+    fn modmail_test() {
+        let config = "
+channel_id = 1
+mod_role = 2
+";
+        assert_eq!(
+            ModMailConfig::from_config(config),
+            ModMailConfig {
+                channel_id: ChannelId::new(1),
+                mod_role: RoleId::new(2),
+                notify: ModMailNotifyConfig::default(),
+            }
+        );
+
+        let config = "
+channel_id = 1
+mod_role = 2
+
+[notify]
+ping_mod_role = true
+alert_channel = 3
+escalate_after_minutes = 30
+";
+        assert_eq!(
+            ModMailConfig::from_config(config).notify,
+            ModMailNotifyConfig {
+                ping_mod_role: true,
+                alert_channel: Some(ChannelId::new(3)),
+                escalate_after_minutes: Some(30),
+                digest_time: None,
+            }
+        );
+    }
 }
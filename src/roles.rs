@@ -0,0 +1,667 @@
+use ::serenity::futures::StreamExt;
+use indexmap::IndexMap;
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    ChannelId, CreateActionRow, CreateButton, CreateSelectMenu, CreateSelectMenuKind,
+    CreateSelectMenuOption, GuildId, MessageId, ReactionType, Role, RoleId, UserId,
+};
+
+use crate::read_conf::{
+    ChoiceMode, ChoiceStyle, RoleButton, RoleChoice, RoleConfig, StickyRoleConfig,
+};
+
+pub const SELECT_PREFIX: &str = "role_select:";
+
+/// Why `role` may not be handed out by reaction on behalf of a member whose highest role sits
+/// at `top_position`, if it may not.
+pub fn check_bindable(role: &Role, top_position: u16, is_owner: bool) -> Result<(), String> {
+    if role.managed {
+        return Err(format!("{} is managed by an integration", role.name));
+    }
+    if is_owner {
+        return Ok(());
+    }
+    if role.position >= top_position {
+        return Err(format!("{} is not below your highest role", role.name));
+    }
+    if role.permissions.administrator() {
+        return Err(format!("{} has the Administrator permission", role.name));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReactionRole {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub emoji: String,
+    pub role_id: RoleId,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RoleExpiry {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub role_id: RoleId,
+    pub expires_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RoleLogEntry {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub role_id: RoleId,
+    pub added: bool,
+    pub at: i64,
+}
+
+impl RoleLogEntry {
+    pub fn describe(&self) -> String {
+        format!(
+            "<t:{}:f> <@{}> {} <@&{}>",
+            self.at,
+            self.user_id,
+            if self.added { "added" } else { "removed" },
+            self.role_id
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RoleSnapshot {
+    pub guild_id: GuildId,
+    pub at: i64,
+    pub counts: IndexMap<RoleId, u64>,
+}
+
+/// Counts how many members of `guild_id` hold each role in `config`.
+pub async fn count_roles(
+    ctx: &serenity::Context,
+    config: &RoleConfig,
+    guild_id: GuildId,
+) -> Result<IndexMap<RoleId, u64>, crate::Error> {
+    let mut counts: IndexMap<RoleId, u64> = config
+        .choices
+        .iter()
+        .flat_map(|c| c.role_ids())
+        .map(|r| (r, 0))
+        .collect();
+    let mut members = guild_id.members_iter(ctx).boxed();
+    while let Some(member) = members.next().await {
+        for role in member?.roles {
+            if let Some(count) = counts.get_mut(&role) {
+                *count += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Renders `counts` as a text bar chart per choice, with the change since `previous` if known.
+pub fn render_role_stats(
+    config: &RoleConfig,
+    counts: &IndexMap<RoleId, u64>,
+    previous: Option<&IndexMap<RoleId, u64>>,
+) -> Vec<String> {
+    const WIDTH: u64 = 12;
+    let mut lines = vec![];
+    for choice in &config.choices {
+        lines.push(format!("## {}", choice.message));
+        let max = choice
+            .role_ids()
+            .filter_map(|r| counts.get(&r))
+            .max()
+            .copied()
+            .unwrap_or(0)
+            .max(1);
+        for button in choice.options.values() {
+            let count = counts.get(&button.role_id).copied().unwrap_or(0);
+            let filled = (count * WIDTH).div_ceil(max) as usize;
+            let bar = format!(
+                "{}{}",
+                "█".repeat(filled),
+                "░".repeat(WIDTH as usize - filled)
+            );
+            let trend = match previous.and_then(|p| p.get(&button.role_id)) {
+                Some(before) => format!(" ({:+} vs last week)", count as i64 - *before as i64),
+                None => String::new(),
+            };
+            lines.push(format!(
+                "`{bar}` {} {}: {count}{trend}",
+                button.emoji, button.label
+            ));
+        }
+    }
+    lines
+}
+
+/// The tracked roles of someone who left the guild, restored when they rejoin.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DepartedMemberRoles {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub roles: Vec<RoleId>,
+    pub left_at: i64,
+}
+
+/// The tracked roles of someone still in the guild, kept current from member updates.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CurrentMemberRoles {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub roles: Vec<RoleId>,
+}
+
+impl StickyRoleConfig {
+    pub fn is_tracked(&self, role: &RoleId) -> bool {
+        self.restore.contains(role) || self.sticky.contains(role)
+    }
+
+    pub fn tracked(&self, roles: &[RoleId]) -> Vec<RoleId> {
+        roles
+            .iter()
+            .filter(|r| self.is_tracked(r))
+            .copied()
+            .collect()
+    }
+
+    /// Sticky roles are always given back, the rest of the allowlist only if the member
+    /// rejoined within `restore_within_days`.
+    pub fn roles_to_restore(&self, snapshot: &DepartedMemberRoles, now: i64) -> Vec<RoleId> {
+        let in_window = self
+            .restore_within_days
+            .is_none_or(|days| now - snapshot.left_at <= days * 86400);
+        snapshot
+            .roles
+            .iter()
+            .filter(|r| self.sticky.contains(r) || (in_window && self.restore.contains(r)))
+            .copied()
+            .collect()
+    }
+}
+
+/// A key that is the same for an emoji whether it was typed into a command or came in on a
+/// reaction event, where custom emoji names and the animated flag may be missing.
+pub fn emoji_key(emoji: &ReactionType) -> String {
+    match emoji {
+        ReactionType::Custom { id, .. } => id.to_string(),
+        ReactionType::Unicode(s) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct RoleChange {
+    pub add: Vec<RoleId>,
+    pub remove: Vec<RoleId>,
+}
+
+impl RoleChoice {
+    pub fn key(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.message)
+    }
+
+    pub fn role_ids(&self) -> impl Iterator<Item = RoleId> + '_ {
+        self.options.values().map(|b| b.role_id)
+    }
+
+    pub fn duration_of(&self, role: RoleId) -> Option<chrono::Duration> {
+        self.options
+            .values()
+            .find(|b| b.role_id == role)
+            .and_then(|b| b.duration)
+    }
+
+    pub fn max_roles(&self) -> Option<usize> {
+        match self.mode {
+            ChoiceMode::Exclusive => Some(1),
+            ChoiceMode::Multiple => self.max,
+        }
+    }
+
+    /// Whether Discord will accept the menu, 25 buttons or select options at most.
+    pub fn check_limits(&self) -> Result<(), String> {
+        let count = self.options.len();
+        if count > 25 {
+            return Err(format!("{count} options, Discord allows at most 25"));
+        }
+        if self.style == ChoiceStyle::Select {
+            // The key goes into the menu's custom ID, which Discord caps at 100 characters
+            if SELECT_PREFIX.len() + self.key().len() > 100 {
+                return Err(format!(
+                    "select menus need an id of at most {} characters",
+                    100 - SELECT_PREFIX.len()
+                ));
+            }
+            let min = self.min.unwrap_or(0);
+            if count == 0 {
+                return Err("a select menu needs at least one option".to_string());
+            }
+            if min > count {
+                return Err(format!("min is {min} but there are only {count} options"));
+            }
+            if let Some(max) = self.max_roles()
+                && min > max
+            {
+                return Err(format!("min is {min} but at most {max} may be picked"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RoleButton {
+    fn reaction(&self) -> ReactionType {
+        self.emoji
+            .parse::<ReactionType>()
+            .unwrap_or_else(|_| panic!("{} cannot be converted to an emoji", self.emoji))
+    }
+}
+
+pub fn role_menu_components(choice: &RoleChoice) -> Vec<CreateActionRow> {
+    match choice.style {
+        ChoiceStyle::Buttons => choice
+            .options
+            .iter()
+            .collect::<Vec<_>>()
+            .chunks(5)
+            .map(|c| {
+                CreateActionRow::Buttons(
+                    c.iter()
+                        .map(|(i, d)| {
+                            CreateButton::new(i.to_string())
+                                .emoji(d.reaction())
+                                .label(d.label.clone())
+                        })
+                        .collect(),
+                )
+            })
+            .collect(),
+        ChoiceStyle::Select => {
+            let options = choice
+                .options
+                .iter()
+                .map(|(i, d)| {
+                    let option = CreateSelectMenuOption::new(d.label.clone(), i.to_string())
+                        .emoji(d.reaction());
+                    match &d.description {
+                        Some(description) => option.description(description.clone()),
+                        None => option,
+                    }
+                })
+                .collect::<Vec<_>>();
+            let max = choice
+                .max_roles()
+                .unwrap_or(options.len())
+                .min(options.len());
+            vec![CreateActionRow::SelectMenu(
+                CreateSelectMenu::new(
+                    format!("{SELECT_PREFIX}{}", choice.key()),
+                    CreateSelectMenuKind::String { options },
+                )
+                .placeholder("Pick your roles")
+                .min_values(choice.min.unwrap_or(0) as u8)
+                .max_values(max as u8),
+            )]
+        }
+    }
+}
+
+/// Works out what clicking `role` in `choice` does for a member holding `held`, or why it is
+/// not allowed.
+pub fn toggle_role(
+    choice: &RoleChoice,
+    held: &[RoleId],
+    role: RoleId,
+) -> Result<RoleChange, String> {
+    let held_in_choice: Vec<RoleId> = choice.role_ids().filter(|r| held.contains(r)).collect();
+
+    if held_in_choice.contains(&role) {
+        if let Some(min) = choice.min
+            && held_in_choice.len() <= min
+        {
+            return Err(format!("You need at least {min} role(s) from this menu"));
+        }
+        return Ok(RoleChange {
+            add: vec![],
+            remove: vec![role],
+        });
+    }
+
+    let change = if choice.mode == ChoiceMode::Exclusive {
+        RoleChange {
+            add: vec![role],
+            remove: held_in_choice,
+        }
+    } else {
+        if let Some(max) = choice.max
+            && held_in_choice.len() >= max
+        {
+            return Err(format!(
+                "You can only pick {max} role(s) from this menu, remove one first"
+            ));
+        }
+        RoleChange {
+            add: vec![role],
+            remove: vec![],
+        }
+    };
+    check_requirements(choice, held, &change)?;
+    Ok(change)
+}
+
+/// Refuses a change that adds a role whose `requires` are not all held afterwards, or which
+/// conflicts with a role that is still held afterwards.
+fn check_requirements(
+    choice: &RoleChoice,
+    held: &[RoleId],
+    change: &RoleChange,
+) -> Result<(), String> {
+    let after: Vec<RoleId> = held
+        .iter()
+        .chain(&change.add)
+        .filter(|r| !change.remove.contains(r))
+        .copied()
+        .collect();
+
+    for button in choice
+        .options
+        .values()
+        .filter(|b| change.add.contains(&b.role_id))
+    {
+        let missing = button
+            .requires
+            .iter()
+            .filter(|r| !after.contains(r))
+            .map(|r| format!("<@&{r}>"))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(format!(
+                "You need {} before you can pick {}",
+                missing.join(", "),
+                button.label
+            ));
+        }
+        let conflicting = button
+            .conflicts
+            .iter()
+            .filter(|r| after.contains(r))
+            .map(|r| format!("<@&{r}>"))
+            .collect::<Vec<_>>();
+        if !conflicting.is_empty() {
+            return Err(format!(
+                "You can't pick {} while you have {}",
+                button.label,
+                conflicting.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn describe_change(change: &RoleChange) -> String {
+    let mentions = |roles: &[RoleId]| {
+        roles
+            .iter()
+            .map(|r| format!("<@&{r}>"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match (change.add.is_empty(), change.remove.is_empty()) {
+        (false, false) => format!(
+            "You added {} and removed {}",
+            mentions(&change.add),
+            mentions(&change.remove)
+        ),
+        (false, true) => format!("You added {}", mentions(&change.add)),
+        (true, false) => format!("You removed {}", mentions(&change.remove)),
+        (true, true) => "Your roles are unchanged".to_string(),
+    }
+}
+
+/// Works out how to make a member holding `held` end up with exactly `selected` out of the roles
+/// in `choice`.
+pub fn select_roles(
+    choice: &RoleChoice,
+    held: &[RoleId],
+    selected: &[RoleId],
+) -> Result<RoleChange, String> {
+    if let Some(min) = choice.min
+        && selected.len() < min
+    {
+        return Err(format!("You need at least {min} role(s) from this menu"));
+    }
+    if let Some(max) = choice.max_roles()
+        && selected.len() > max
+    {
+        return Err(format!("You can only pick {max} role(s) from this menu"));
+    }
+
+    let change = RoleChange {
+        add: selected
+            .iter()
+            .filter(|r| !held.contains(r))
+            .copied()
+            .collect(),
+        remove: choice
+            .role_ids()
+            .filter(|r| held.contains(r) && !selected.contains(r))
+            .collect(),
+    };
+    check_requirements(choice, held, &change)?;
+    Ok(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_conf::RoleConfig;
+
+    #[test]
+    fn bindable_test() {
+        let role = |position: u16, permissions: &str, managed: bool| -> Role {
+            serde_json::from_value(serde_json::json!({
+                "id": "1",
+                "name": "role",
+                "color": 0,
+                "hoist": false,
+                "managed": managed,
+                "mentionable": false,
+                "permissions": permissions,
+                "position": position,
+            }))
+            .unwrap()
+        };
+
+        assert!(check_bindable(&role(3, "0", false), 5, false).is_ok());
+        assert!(check_bindable(&role(5, "0", false), 5, false).is_err());
+        assert!(check_bindable(&role(6, "0", false), 5, true).is_ok());
+        assert!(check_bindable(&role(3, "8", false), 5, false).is_err());
+        assert!(check_bindable(&role(3, "0", true), 5, true).is_err());
+    }
+
+    #[test]
+    fn role_menus_test() {
+        let menus: IndexMap<ChannelId, IndexMap<String, MessageId>> = IndexMap::from([(
+            ChannelId::new(5),
+            IndexMap::from([
+                ("colours".to_string(), MessageId::new(1)),
+                ("Pings".to_string(), MessageId::new(2)),
+            ]),
+        )]);
+        let stored = serde_json::to_string(&menus).unwrap();
+        assert_eq!(stored, r#"{"5":{"colours":"1","Pings":"2"}}"#);
+        assert_eq!(
+            serde_json::from_str::<IndexMap<ChannelId, IndexMap<String, MessageId>>>(&stored)
+                .unwrap(),
+            menus
+        );
+    }
+
+    #[test]
+    fn limits_test() {
+        let options = (0..26)
+            .map(|i| {
+                format!(
+                    "    o{i} = {{ emoji = \"{i}\", label = \"l\", role_id = {} }}",
+                    i + 1
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config = RoleConfig::from_config(&format!(
+            "
+[[choices]]
+message = \"Too many\"
+style = \"select\"
+[choices.options]
+{options}
+
+[[choices]]
+message = \"Min too high\"
+style = \"select\"
+min = 3
+[choices.options]
+    a = {{ emoji = \"1\", label = \"a\", role_id = 1 }}
+    b = {{ emoji = \"2\", label = \"b\", role_id = 2 }}
+
+[[choices]]
+message = \"Fine\"
+style = \"select\"
+min = 1
+[choices.options]
+    a = {{ emoji = \"1\", label = \"a\", role_id = 1 }}
+"
+        ));
+        let results = config
+            .choices
+            .iter()
+            .map(|c| c.check_limits().is_ok())
+            .collect::<Vec<_>>();
+        assert_eq!(results, vec![false, false, true]);
+    }
+
+    #[test]
+    fn toggle_test() {
+        let config = RoleConfig::from_config(
+            "
+[[choices]]
+message = \"Pronouns\"
+mode = \"exclusive\"
+min = 1
+[choices.options]
+    a = { emoji = \"1\", label = \"a\", role_id = 1 }
+    b = { emoji = \"2\", label = \"b\", role_id = 2 }
+
+[[choices]]
+message = \"Games\"
+max = 2
+[choices.options]
+    c = { emoji = \"3\", label = \"c\", role_id = 3 }
+    d = { emoji = \"4\", label = \"d\", role_id = 4 }
+    e = { emoji = \"5\", label = \"e\", role_id = 5 }
+    f = { emoji = \"6\", label = \"f\", role_id = 6, requires = [7], conflicts = [8] }
+",
+        );
+        let (exclusive, multiple) = (&config.choices[0], &config.choices[1]);
+        let r = RoleId::new;
+
+        assert_eq!(
+            toggle_role(exclusive, &[r(1), r(9)], r(2)),
+            Ok(RoleChange {
+                add: vec![r(2)],
+                remove: vec![r(1)],
+            })
+        );
+        assert!(toggle_role(exclusive, &[r(1)], r(1)).is_err());
+
+        assert_eq!(
+            toggle_role(multiple, &[r(3)], r(4)),
+            Ok(RoleChange {
+                add: vec![r(4)],
+                remove: vec![],
+            })
+        );
+        assert!(toggle_role(multiple, &[r(3), r(4)], r(5)).is_err());
+        assert_eq!(
+            toggle_role(multiple, &[r(3), r(4)], r(4)),
+            Ok(RoleChange {
+                add: vec![],
+                remove: vec![r(4)],
+            })
+        );
+
+        assert_eq!(
+            select_roles(multiple, &[r(3), r(4), r(9)], &[r(4), r(5)]),
+            Ok(RoleChange {
+                add: vec![r(5)],
+                remove: vec![r(3)],
+            })
+        );
+        assert!(select_roles(multiple, &[], &[r(3), r(4), r(5)]).is_err());
+        assert!(select_roles(exclusive, &[r(1)], &[]).is_err());
+
+        assert!(toggle_role(multiple, &[], r(6)).is_err());
+        assert!(toggle_role(multiple, &[r(7), r(8)], r(6)).is_err());
+        assert!(toggle_role(multiple, &[r(7)], r(6)).is_ok());
+        assert!(select_roles(multiple, &[r(7), r(8)], &[r(3), r(6)]).is_err());
+    }
+
+    #[test]
+    fn emoji_key_test() {
+        let typed = "<:party:123>".parse::<ReactionType>().unwrap();
+        let event = ReactionType::Custom {
+            animated: false,
+            id: serenity::all::EmojiId::new(123),
+            name: None,
+        };
+        assert_eq!(emoji_key(&typed), emoji_key(&event));
+        assert_eq!(emoji_key(&"👍".parse().unwrap()), "👍");
+    }
+
+    #[test]
+    fn render_stats_test() {
+        let config = RoleConfig::from_config(
+            "
+[[choices]]
+message = \"Games\"
+[choices.options]
+    a = { emoji = \"1\", label = \"a\", role_id = 1 }
+    b = { emoji = \"2\", label = \"b\", role_id = 2 }
+",
+        );
+        let counts = IndexMap::from([(RoleId::new(1), 6), (RoleId::new(2), 3)]);
+        let previous = IndexMap::from([(RoleId::new(1), 8)]);
+
+        assert_eq!(
+            render_role_stats(&config, &counts, Some(&previous)),
+            vec![
+                "## Games".to_string(),
+                "`████████████` 1 a: 6 (-2 vs last week)".to_string(),
+                "`██████░░░░░░` 2 b: 3".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn restore_test() {
+        let config = StickyRoleConfig::from_config(
+            "
+restore = [1, 2]
+sticky = [3]
+restore_within_days = 7
+",
+        );
+        let r = RoleId::new;
+        let snapshot = DepartedMemberRoles {
+            guild_id: GuildId::new(1),
+            user_id: UserId::new(1),
+            roles: vec![r(1), r(3), r(4)],
+            left_at: 0,
+        };
+        assert!(config.is_tracked(&r(3)) && !config.is_tracked(&r(4)));
+        assert_eq!(config.roles_to_restore(&snapshot, 86400), vec![r(1), r(3)]);
+        assert_eq!(config.roles_to_restore(&snapshot, 8 * 86400), vec![r(3)]);
+    }
+}
@@ -0,0 +1,135 @@
+use serenity::all::{ComponentInteractionDataKind, RoleId};
+
+use crate::{
+    read_conf::{ChoiceStyle, RoleChoice, RoleConfig},
+    roles::SELECT_PREFIX,
+};
+
+pub const MODMAIL_CLOSE: &str = "modmail_button";
+
+#[derive(Debug, PartialEq)]
+pub enum Component<'a> {
+    ModmailClose,
+    RoleButton {
+        choice: &'a RoleChoice,
+        role_id: RoleId,
+    },
+    RoleSelect {
+        choice: &'a RoleChoice,
+        selected: Vec<RoleId>,
+    },
+    /// Handled by a collector, such as the buttons from `register`.
+    Collector,
+    /// Left over from an older `roles.toml` or otherwise not ours.
+    Unknown,
+}
+
+pub fn route<'a>(
+    config: &'a RoleConfig,
+    custom_id: &str,
+    kind: &ComponentInteractionDataKind,
+) -> Component<'a> {
+    if custom_id == MODMAIL_CLOSE {
+        return Component::ModmailClose;
+    }
+    if custom_id.starts_with("register.") || custom_id.starts_with("unregister.") {
+        return Component::Collector;
+    }
+
+    match kind {
+        ComponentInteractionDataKind::StringSelect { values } => custom_id
+            .strip_prefix(SELECT_PREFIX)
+            .and_then(|key| config.choices.iter().find(|c| c.key() == key))
+            .filter(|c| c.style == ChoiceStyle::Select)
+            .and_then(|choice| {
+                let selected = values
+                    .iter()
+                    .map(|v| choice.options.get(v).map(|b| b.role_id))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Component::RoleSelect { choice, selected })
+            })
+            .unwrap_or(Component::Unknown),
+        ComponentInteractionDataKind::Button => config
+            .choices
+            .iter()
+            .filter(|c| c.style == ChoiceStyle::Buttons)
+            .find_map(|choice| {
+                choice
+                    .options
+                    .get(custom_id)
+                    .map(|b| Component::RoleButton {
+                        choice,
+                        role_id: b.role_id,
+                    })
+            })
+            .unwrap_or(Component::Unknown),
+        _ => Component::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_test() {
+        let config = RoleConfig::from_config(
+            "
+[[choices]]
+message = \"Buttons\"
+[choices.options]
+    a = { emoji = \"1\", label = \"a\", role_id = 1 }
+
+[[choices]]
+id = \"pick\"
+message = \"Select\"
+style = \"select\"
+[choices.options]
+    b = { emoji = \"2\", label = \"b\", role_id = 2 }
+    c = { emoji = \"3\", label = \"c\", role_id = 3 }
+",
+        );
+        let button = ComponentInteractionDataKind::Button;
+        let select = |v: &[&str]| ComponentInteractionDataKind::StringSelect {
+            values: v.iter().map(|s| s.to_string()).collect(),
+        };
+
+        assert_eq!(
+            route(&config, "modmail_button", &button),
+            Component::ModmailClose
+        );
+        assert_eq!(
+            route(&config, "register.guild", &button),
+            Component::Collector
+        );
+        assert_eq!(
+            route(&config, "a", &button),
+            Component::RoleButton {
+                choice: &config.choices[0],
+                role_id: RoleId::new(1),
+            }
+        );
+        assert_eq!(
+            route(&config, "role_select:pick", &select(&["b", "c"])),
+            Component::RoleSelect {
+                choice: &config.choices[1],
+                selected: vec![RoleId::new(2), RoleId::new(3)],
+            }
+        );
+
+        assert_eq!(route(&config, "removed", &button), Component::Unknown);
+        assert_eq!(route(&config, "b", &button), Component::Unknown);
+        assert_eq!(
+            route(&config, "role_select:pick", &select(&["gone"])),
+            Component::Unknown
+        );
+        assert_eq!(
+            route(&config, "role_select:Buttons", &select(&["a"])),
+            Component::Unknown
+        );
+        assert_eq!(
+            route(&config, "role_select:1", &select(&["b"])),
+            Component::Unknown
+        );
+    }
+}
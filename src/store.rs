@@ -0,0 +1,93 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+pub struct Store<T> {
+    path: PathBuf,
+    data: Mutex<T>,
+    /// The number of the last change written, so a slow write never overwrites a newer one.
+    written: Arc<Mutex<u64>>,
+    changes: Mutex<u64>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> Store<T> {
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let data = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s)
+                .unwrap_or_else(|e| panic!("{} is not valid: {e}", path.display())),
+            Err(_) => T::default(),
+        };
+        Self {
+            path,
+            data: Mutex::new(data),
+            written: Arc::new(Mutex::new(0)),
+            changes: Mutex::new(0),
+        }
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.data.lock().unwrap())
+    }
+
+    /// Changes the data and saves it, off the async runtime's worker threads when there is one.
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let (r, json, change) = {
+            let mut data = self.data.lock().unwrap();
+            let r = f(&mut data);
+            let mut changes = self.changes.lock().unwrap();
+            *changes += 1;
+            (r, serde_json::to_string_pretty(&*data).unwrap(), *changes)
+        };
+
+        let path = self.path.clone();
+        let written = self.written.clone();
+        let save = move || {
+            let mut written = written.lock().unwrap();
+            if change < *written {
+                return;
+            }
+            if let Err(e) = write_atomic(&path, &json) {
+                eprintln!("Failed to save {}: {e}", path.display());
+            }
+            *written = change;
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(save)),
+            Err(_) => save(),
+        }
+        r
+    }
+}
+
+/// Writes through a temporary file, so a crash never leaves half a file behind.
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_test() {
+        let path = std::env::temp_dir().join(format!("tdl_store_test_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store: Store<Vec<u64>> = Store::load(&path);
+        assert_eq!(store.read(|v| v.len()), 0);
+        store.update(|v| v.extend([1, 2, 3]));
+
+        let reloaded: Store<Vec<u64>> = Store::load(&path);
+        assert_eq!(reloaded.read(|v| v.clone()), vec![1, 2, 3]);
+        assert!(!path.with_extension("json.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,421 @@
+use std::{collections::BTreeMap, fs};
+
+use ::serenity::futures::StreamExt;
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    ChannelId, CreateAllowedMentions, CreateMessage, GuildChannel, Message, PermissionOverwrite,
+    PermissionOverwriteType, Permissions, UserId,
+};
+
+use crate::MOD_MAIL_CONFIG;
+
+pub const TRANSCRIPT_DIR: &str = "transcripts";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct TicketStore {
+    pub next_number: u64,
+    pub tickets: Vec<Ticket>,
+}
+
+/// What a ticket is about, picked by whoever opens it.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    poise::ChoiceParameter,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum TicketCategory {
+    Report,
+    Appeal,
+    Question,
+    #[default]
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ticket {
+    pub number: u64,
+    pub channel_id: ChannelId,
+    #[serde(default)]
+    pub channel_name: String,
+    #[serde(default)]
+    pub category: Option<ChannelId>,
+    pub title: String,
+    /// What the ticket is about, as opposed to `category`, the channel category it lives in.
+    #[serde(default)]
+    pub kind: TicketCategory,
+    pub user: UserId,
+    #[serde(default)]
+    pub participants: Vec<UserId>,
+    pub opened_at: i64,
+    pub closed_at: Option<i64>,
+    #[serde(default)]
+    pub first_response_at: Option<i64>,
+    #[serde(default)]
+    pub escalated: bool,
+    #[serde(default)]
+    pub responders: Vec<UserId>,
+    pub history: Vec<TicketEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TicketEvent {
+    pub at: i64,
+    pub by: UserId,
+    pub action: TicketAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TicketAction {
+    Opened,
+    Snippet { name: String },
+    ParticipantAdded { user: UserId },
+    ParticipantRemoved { user: UserId },
+    Closed,
+    Reopened,
+}
+
+impl TicketStore {
+    pub fn open(
+        &mut self,
+        channel: &GuildChannel,
+        title: String,
+        kind: TicketCategory,
+        user: UserId,
+        by: UserId,
+    ) -> u64 {
+        self.next_number += 1;
+        let now = chrono::Utc::now().timestamp();
+        self.tickets.push(Ticket {
+            number: self.next_number,
+            channel_id: channel.id,
+            channel_name: channel.name.clone(),
+            category: channel.parent_id,
+            title,
+            kind,
+            user,
+            participants: vec![user],
+            opened_at: now,
+            closed_at: None,
+            first_response_at: None,
+            escalated: false,
+            responders: vec![],
+            history: vec![TicketEvent {
+                at: now,
+                by,
+                action: TicketAction::Opened,
+            }],
+        });
+        self.next_number
+    }
+
+    pub fn by_channel(&self, channel_id: ChannelId) -> Option<&Ticket> {
+        self.tickets
+            .iter()
+            .find(|t| t.channel_id == channel_id && t.closed_at.is_none())
+    }
+
+    pub fn by_channel_mut(&mut self, channel_id: ChannelId) -> Option<&mut Ticket> {
+        self.tickets
+            .iter_mut()
+            .find(|t| t.channel_id == channel_id && t.closed_at.is_none())
+    }
+
+    pub fn by_number_mut(&mut self, number: u64) -> Option<&mut Ticket> {
+        self.tickets.iter_mut().find(|t| t.number == number)
+    }
+}
+
+impl Ticket {
+    pub fn log(&mut self, by: UserId, action: TicketAction) {
+        self.history.push(TicketEvent {
+            at: chrono::Utc::now().timestamp(),
+            by,
+            action,
+        });
+    }
+
+    /// Whether a staff response from `by` would change the response bookkeeping.
+    pub fn awaits_response_from(&self, by: UserId) -> bool {
+        self.first_response_at.is_none() || !self.responders.contains(&by)
+    }
+
+    /// Records a staff response, keeping the time of the first one.
+    pub fn record_response(&mut self, by: UserId, at: i64) {
+        self.first_response_at.get_or_insert(at);
+        if !self.responders.contains(&by) {
+            self.responders.push(by);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct TicketStats {
+    pub opened: usize,
+    pub closed: usize,
+    pub median_first_response: Option<i64>,
+    pub median_close: Option<i64>,
+    pub per_category: BTreeMap<TicketCategory, usize>,
+    pub per_moderator: BTreeMap<UserId, (usize, usize)>,
+}
+
+fn median(mut values: Vec<i64>) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2
+    } else {
+        values[mid]
+    })
+}
+
+impl TicketStats {
+    /// Stats for tickets opened or closed at or after `since`. Moderator counts are
+    /// `(tickets responded in, tickets closed)`.
+    pub fn compute(tickets: &[Ticket], since: i64) -> Self {
+        let mut stats = TicketStats::default();
+        let opened: Vec<_> = tickets.iter().filter(|t| t.opened_at >= since).collect();
+        let closed: Vec<_> = tickets
+            .iter()
+            .filter(|t| t.closed_at.is_some_and(|c| c >= since))
+            .collect();
+
+        stats.opened = opened.len();
+        stats.closed = closed.len();
+        stats.median_first_response = median(
+            opened
+                .iter()
+                .filter_map(|t| t.first_response_at.map(|r| r - t.opened_at))
+                .collect(),
+        );
+        stats.median_close = median(
+            closed
+                .iter()
+                .filter_map(|t| t.closed_at.map(|c| c - t.opened_at))
+                .collect(),
+        );
+
+        for t in &opened {
+            *stats.per_category.entry(t.kind).or_default() += 1;
+            for r in &t.responders {
+                stats.per_moderator.entry(*r).or_default().0 += 1;
+            }
+        }
+        for t in &closed {
+            let closer = t
+                .history
+                .iter()
+                .rev()
+                .find(|e| matches!(e.action, TicketAction::Closed));
+            if let Some(e) = closer {
+                stats.per_moderator.entry(e.by).or_default().1 += 1;
+            }
+        }
+        stats
+    }
+}
+
+pub fn format_duration(secs: i64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+pub fn transcript_path(number: u64) -> String {
+    format!("{TRANSCRIPT_DIR}/ticket-{number}.txt")
+}
+
+fn transcript_line(m: &Message) -> String {
+    let mut line = format!(
+        "[{}] {}: {}",
+        m.timestamp.format("%Y-%m-%d %H:%M:%S"),
+        m.author.name,
+        m.content
+    );
+    for a in &m.attachments {
+        line.push_str(&format!(" <{}>", a.url));
+    }
+    line
+}
+
+pub async fn archive_transcript(
+    ctx: &serenity::Context,
+    channel_id: ChannelId,
+    number: u64,
+) -> Result<(), crate::Error> {
+    let mut messages = channel_id.messages_iter(&ctx).boxed();
+    let mut lines = vec![];
+    while let Some(m) = messages.next().await {
+        lines.push(transcript_line(&m?));
+    }
+    lines.reverse();
+
+    fs::create_dir_all(TRANSCRIPT_DIR)?;
+    fs::write(transcript_path(number), lines.join("\n"))?;
+    Ok(())
+}
+
+pub async fn notify_staff(
+    ctx: &serenity::Context,
+    ticket_channel: ChannelId,
+    content: String,
+) -> Result<(), crate::Error> {
+    let notify = &MOD_MAIL_CONFIG.notify;
+    if !notify.ping_mod_role && notify.alert_channel.is_none() {
+        return Ok(());
+    }
+
+    // Ticket titles come from users, so only the mod role may ever be pinged
+    let mut m = CreateMessage::new();
+    let content = if notify.ping_mod_role {
+        m = m.allowed_mentions(CreateAllowedMentions::new().roles(vec![MOD_MAIL_CONFIG.mod_role]));
+        format!("<@&{}> {content}", MOD_MAIL_CONFIG.mod_role)
+    } else {
+        m = m.allowed_mentions(CreateAllowedMentions::new());
+        content
+    };
+    notify
+        .alert_channel
+        .unwrap_or(ticket_channel)
+        .send_message(ctx, m.content(content))
+        .await?;
+    Ok(())
+}
+
+pub fn participant_overwrite(kind: PermissionOverwriteType) -> PermissionOverwrite {
+    PermissionOverwrite {
+        allow: Permissions::VIEW_CHANNEL
+            | Permissions::SEND_MESSAGES
+            | Permissions::READ_MESSAGE_HISTORY,
+        deny: Permissions::CREATE_PUBLIC_THREADS | Permissions::CREATE_PRIVATE_THREADS,
+        kind,
+    }
+}
+
+pub fn render_snippet(template: &str, user: UserId, ticket: u64, moderator: UserId) -> String {
+    template
+        .replace("{user}", &format!("<@{user}>"))
+        .replace("{ticket}", &ticket.to_string())
+        .replace("{mod}", &format!("<@{moderator}>"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet_test() {
+        assert_eq!(
+            render_snippet(
+                "Hi {user}, ticket {ticket} is handled by {mod}. {unknown}",
+                UserId::new(1),
+                7,
+                UserId::new(2)
+            ),
+            "Hi <@1>, ticket 7 is handled by <@2>. {unknown}"
+        );
+    }
+
+    #[test]
+    fn stats_test() {
+        let mut store = TicketStore::default();
+        let channel = GuildChannel::default();
+        for _ in 0..3 {
+            store.open(
+                &channel,
+                "t".into(),
+                TicketCategory::Other,
+                UserId::new(1),
+                UserId::new(1),
+            );
+        }
+        for (t, (opened, response, closed)) in store.tickets.iter_mut().zip([
+            (100, Some(160), Some(400)),
+            (200, Some(500), None),
+            (0, None, Some(150)),
+        ]) {
+            t.opened_at = opened;
+            t.first_response_at = response;
+            t.closed_at = closed;
+            t.responders = vec![UserId::new(5)];
+            if closed.is_some() {
+                t.log(UserId::new(6), TicketAction::Closed);
+            }
+        }
+
+        let stats = TicketStats::compute(&store.tickets, 100);
+        assert_eq!(stats.opened, 2);
+        assert_eq!(stats.closed, 2);
+        assert_eq!(stats.median_first_response, Some(180));
+        assert_eq!(stats.median_close, Some(225));
+        assert_eq!(stats.per_category.get(&TicketCategory::Other), Some(&2));
+        assert_eq!(stats.per_moderator.get(&UserId::new(5)), Some(&(2, 0)));
+        assert_eq!(stats.per_moderator.get(&UserId::new(6)), Some(&(0, 2)));
+    }
+
+    #[test]
+    fn response_test() {
+        let mut store = TicketStore::default();
+        store.open(
+            &GuildChannel::default(),
+            "t".into(),
+            TicketCategory::Question,
+            UserId::new(1),
+            UserId::new(1),
+        );
+        let t = &mut store.tickets[0];
+        assert!(t.awaits_response_from(UserId::new(5)));
+        t.record_response(UserId::new(5), 100);
+        t.record_response(UserId::new(6), 200);
+        t.record_response(UserId::new(5), 300);
+        assert!(!t.awaits_response_from(UserId::new(5)));
+        assert_eq!(t.first_response_at, Some(100));
+        assert_eq!(t.responders, vec![UserId::new(5), UserId::new(6)]);
+    }
+
+    #[test]
+    fn ticket_numbering_test() {
+        let mut store = TicketStore::default();
+        let mut channel = GuildChannel::default();
+        channel.id = ChannelId::new(10);
+        let a = store.open(
+            &channel,
+            "a".into(),
+            TicketCategory::Report,
+            UserId::new(1),
+            UserId::new(1),
+        );
+        channel.id = ChannelId::new(11);
+        let b = store.open(
+            &channel,
+            "b".into(),
+            TicketCategory::Other,
+            UserId::new(2),
+            UserId::new(3),
+        );
+        assert_eq!((a, b), (1, 2));
+        assert_eq!(store.by_channel(ChannelId::new(11)).unwrap().number, 2);
+
+        let t = store.by_channel_mut(ChannelId::new(10)).unwrap();
+        t.closed_at = Some(0);
+        assert!(store.by_channel(ChannelId::new(10)).is_none());
+    }
+}
@@ -0,0 +1,1465 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use indexmap::IndexMap;
+use reqwest::Client as HttpClient;
+use serde::Deserialize;
+use serenity::{
+    all::{ChannelId, Embed, MessageId, RoleId, Timestamp, UserId},
+    async_trait,
+};
+
+use crate::{
+    Error,
+    handler::chunk_lines,
+    read_conf::{BackendConfig, ReactionTranslateConfig},
+};
+
+/// Language codes understood by the translation backend, with their names.
+pub const LANGUAGES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("az", "Azerbaijani"),
+    ("bg", "Bulgarian"),
+    ("bn", "Bengali"),
+    ("ca", "Catalan"),
+    ("cs", "Czech"),
+    ("da", "Danish"),
+    ("de", "German"),
+    ("el", "Greek"),
+    ("en", "English"),
+    ("eo", "Esperanto"),
+    ("es", "Spanish"),
+    ("et", "Estonian"),
+    ("eu", "Basque"),
+    ("fa", "Persian"),
+    ("fi", "Finnish"),
+    ("fr", "French"),
+    ("ga", "Irish"),
+    ("gl", "Galician"),
+    ("he", "Hebrew"),
+    ("hi", "Hindi"),
+    ("hu", "Hungarian"),
+    ("id", "Indonesian"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("ky", "Kyrgyz"),
+    ("lt", "Lithuanian"),
+    ("lv", "Latvian"),
+    ("ms", "Malay"),
+    ("nb", "Norwegian"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ro", "Romanian"),
+    ("ru", "Russian"),
+    ("sk", "Slovak"),
+    ("sl", "Slovenian"),
+    ("sq", "Albanian"),
+    ("sv", "Swedish"),
+    ("th", "Thai"),
+    ("tl", "Tagalog"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("ur", "Urdu"),
+    ("vi", "Vietnamese"),
+    ("zh-Hans", "Chinese (Simplified)"),
+    ("zh-Hant", "Chinese (Traditional)"),
+];
+
+/// The language most people mean when reacting with a country's flag, by ISO 3166 code.
+const FLAG_LANGUAGES: &[(&str, &str)] = &[
+    // English
+    ("AG", "en"),
+    ("AI", "en"),
+    ("AS", "en"),
+    ("AU", "en"),
+    ("BB", "en"),
+    ("BM", "en"),
+    ("BS", "en"),
+    ("BW", "en"),
+    ("BZ", "en"),
+    ("CA", "en"),
+    ("DM", "en"),
+    ("FJ", "en"),
+    ("FK", "en"),
+    ("GB", "en"),
+    ("GD", "en"),
+    ("GH", "en"),
+    ("GI", "en"),
+    ("GM", "en"),
+    ("GU", "en"),
+    ("GY", "en"),
+    ("IO", "en"),
+    ("JM", "en"),
+    ("KE", "en"),
+    ("KN", "en"),
+    ("KY", "en"),
+    ("LC", "en"),
+    ("LR", "en"),
+    ("MP", "en"),
+    ("MS", "en"),
+    ("MT", "en"),
+    ("NG", "en"),
+    ("NZ", "en"),
+    ("PN", "en"),
+    ("SG", "en"),
+    ("SH", "en"),
+    ("SL", "en"),
+    ("SS", "en"),
+    ("TC", "en"),
+    ("TT", "en"),
+    ("UG", "en"),
+    ("UM", "en"),
+    ("US", "en"),
+    ("VC", "en"),
+    ("VG", "en"),
+    ("VI", "en"),
+    ("ZA", "en"),
+    ("ZM", "en"),
+    ("ZW", "en"),
+    // Spanish
+    ("AR", "es"),
+    ("BO", "es"),
+    ("CL", "es"),
+    ("CO", "es"),
+    ("CR", "es"),
+    ("CU", "es"),
+    ("DO", "es"),
+    ("EA", "es"),
+    ("EC", "es"),
+    ("ES", "es"),
+    ("GQ", "es"),
+    ("GT", "es"),
+    ("HN", "es"),
+    ("IC", "es"),
+    ("MX", "es"),
+    ("NI", "es"),
+    ("PA", "es"),
+    ("PE", "es"),
+    ("PR", "es"),
+    ("PY", "es"),
+    ("SV", "es"),
+    ("UY", "es"),
+    ("VE", "es"),
+    // Portuguese
+    ("AO", "pt"),
+    ("BR", "pt"),
+    ("CV", "pt"),
+    ("GW", "pt"),
+    ("MZ", "pt"),
+    ("PT", "pt"),
+    ("ST", "pt"),
+    ("TL", "pt"),
+    // French
+    ("BF", "fr"),
+    ("BI", "fr"),
+    ("BJ", "fr"),
+    ("BL", "fr"),
+    ("CD", "fr"),
+    ("CF", "fr"),
+    ("CG", "fr"),
+    ("CI", "fr"),
+    ("CM", "fr"),
+    ("DJ", "fr"),
+    ("FR", "fr"),
+    ("GA", "fr"),
+    ("GF", "fr"),
+    ("GN", "fr"),
+    ("GP", "fr"),
+    ("HT", "fr"),
+    ("KM", "fr"),
+    ("LU", "fr"),
+    ("MC", "fr"),
+    ("MF", "fr"),
+    ("MG", "fr"),
+    ("ML", "fr"),
+    ("MQ", "fr"),
+    ("NC", "fr"),
+    ("NE", "fr"),
+    ("PF", "fr"),
+    ("PM", "fr"),
+    ("RE", "fr"),
+    ("RW", "fr"),
+    ("SN", "fr"),
+    ("TD", "fr"),
+    ("TF", "fr"),
+    ("TG", "fr"),
+    ("WF", "fr"),
+    ("YT", "fr"),
+    // German
+    ("AT", "de"),
+    ("CH", "de"),
+    ("DE", "de"),
+    ("LI", "de"),
+    // Italian
+    ("IT", "it"),
+    ("SM", "it"),
+    ("VA", "it"),
+    // Dutch
+    ("AW", "nl"),
+    ("BE", "nl"),
+    ("BQ", "nl"),
+    ("CW", "nl"),
+    ("NL", "nl"),
+    ("SR", "nl"),
+    ("SX", "nl"),
+    // Nordic and Baltic
+    ("AX", "sv"),
+    ("BV", "nb"),
+    ("DK", "da"),
+    ("EE", "et"),
+    ("FI", "fi"),
+    ("FO", "da"),
+    ("GL", "da"),
+    ("LT", "lt"),
+    ("LV", "lv"),
+    ("NO", "nb"),
+    ("SE", "sv"),
+    ("SJ", "nb"),
+    // Central and Eastern Europe
+    ("AL", "sq"),
+    ("BG", "bg"),
+    ("BY", "ru"),
+    ("CY", "el"),
+    ("CZ", "cs"),
+    ("GR", "el"),
+    ("HU", "hu"),
+    ("MD", "ro"),
+    ("PL", "pl"),
+    ("RO", "ro"),
+    ("RU", "ru"),
+    ("SI", "sl"),
+    ("SK", "sk"),
+    ("UA", "uk"),
+    ("XK", "sq"),
+    // Elsewhere in Europe
+    ("AD", "ca"),
+    ("IE", "ga"),
+    // Middle East and North Africa
+    ("AE", "ar"),
+    ("AF", "fa"),
+    ("AZ", "az"),
+    ("BH", "ar"),
+    ("DZ", "ar"),
+    ("EG", "ar"),
+    ("EH", "ar"),
+    ("IL", "he"),
+    ("IQ", "ar"),
+    ("IR", "fa"),
+    ("JO", "ar"),
+    ("KW", "ar"),
+    ("LB", "ar"),
+    ("LY", "ar"),
+    ("MA", "ar"),
+    ("MR", "ar"),
+    ("OM", "ar"),
+    ("PS", "ar"),
+    ("QA", "ar"),
+    ("SA", "ar"),
+    ("SD", "ar"),
+    ("SY", "ar"),
+    ("TN", "ar"),
+    ("TR", "tr"),
+    ("YE", "ar"),
+    // Asia
+    ("BD", "bn"),
+    ("BN", "ms"),
+    ("CN", "zh-Hans"),
+    ("HK", "zh-Hant"),
+    ("ID", "id"),
+    ("IN", "hi"),
+    ("JP", "ja"),
+    ("KG", "ky"),
+    ("KP", "ko"),
+    ("KR", "ko"),
+    ("KZ", "ru"),
+    ("MO", "zh-Hant"),
+    ("MY", "ms"),
+    ("PH", "tl"),
+    ("PK", "ur"),
+    ("TH", "th"),
+    ("TW", "zh-Hant"),
+    ("VN", "vi"),
+];
+
+/// The ISO 3166 country code of a flag emoji, made of exactly two regional indicators.
+pub fn flag_country(emoji: &str) -> Option<String> {
+    let letters = emoji
+        .chars()
+        .map(|c| match c {
+            '\u{1f1e6}'..='\u{1f1ff}' => char::from_u32(c as u32 - 0x1f1e6 + 'A' as u32),
+            _ => None,
+        })
+        .collect::<Option<String>>()?;
+    (letters.len() == 2).then_some(letters)
+}
+
+/// The language to translate to for a country's flag, preferring `overrides` from the config.
+pub fn flag_language(country: &str, overrides: &IndexMap<String, String>) -> Option<String> {
+    overrides
+        .get(country)
+        .map(String::as_str)
+        .or_else(|| {
+            FLAG_LANGUAGES
+                .iter()
+                .find(|(c, _)| *c == country)
+                .map(|(_, l)| *l)
+        })
+        .map(str::to_string)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Translated {
+    pub text: String,
+    /// The source language the backend detected, if it reports one.
+    pub source: Option<String>,
+}
+
+#[async_trait]
+pub trait Translator: Send + Sync {
+    /// Translates `text` into `target`, detecting the source language when `source` is `None`.
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&str>,
+        target: &str,
+    ) -> Result<Translated, Error>;
+
+    /// The languages `text` may be in, most likely first. Backends without a detect endpoint
+    /// report the source language they detect while translating.
+    async fn detect(&self, text: &str) -> Result<Vec<Detection>, Error> {
+        let translated = self.translate(text, None, "en").await?;
+        let language = translated
+            .source
+            .ok_or("The translation backend does not report source languages")?;
+        Ok(vec![Detection {
+            language,
+            confidence: None,
+        }])
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Detection {
+    pub language: String,
+    /// From 0 to 100, when the backend reports it.
+    pub confidence: Option<f64>,
+}
+
+pub fn build_translator(config: &BackendConfig, client: HttpClient) -> Arc<dyn Translator> {
+    match config {
+        BackendConfig::LibreTranslate { url, api_key } => Arc::new(LibreTranslate {
+            client,
+            url: url.clone().or_else(|| env::var("TRANSLATE_URL").ok()),
+            api_key: api_key.clone(),
+        }),
+        BackendConfig::DeepL { url, auth_key } => Arc::new(DeepL {
+            client,
+            url: url.clone(),
+            auth_key: auth_key.clone(),
+        }),
+        BackendConfig::Template {
+            url,
+            body,
+            headers,
+            text_pointer,
+            source_pointer,
+        } => Arc::new(JsonTemplate {
+            client,
+            url: url.clone(),
+            body: body.clone(),
+            headers: headers.clone(),
+            text_pointer: text_pointer.clone(),
+            source_pointer: source_pointer.clone(),
+        }),
+    }
+}
+
+pub struct LibreTranslate {
+    client: HttpClient,
+    /// Missing when neither the config nor the environment name one, failing each request.
+    url: Option<String>,
+    api_key: Option<String>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+struct LibreTranslation {
+    translatedText: String,
+    detectedLanguage: Option<Detection>,
+}
+
+impl LibreTranslate {
+    fn url(&self) -> Result<&str, Error> {
+        self.url.as_deref().ok_or_else(|| {
+            "No translation url configured, set TRANSLATE_URL or translate.toml".into()
+        })
+    }
+}
+
+#[async_trait]
+impl Translator for LibreTranslate {
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&str>,
+        target: &str,
+    ) -> Result<Translated, Error> {
+        let res = self
+            .client
+            .post(self.url()?)
+            .json(&serde_json::json!({
+                "q": text,
+                "source": source.unwrap_or("auto"),
+                "target": target,
+                "format": "text",
+                "api_key": self.api_key,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<LibreTranslation>()
+            .await?;
+        Ok(Translated {
+            text: res.translatedText,
+            source: res
+                .detectedLanguage
+                .map(|d| d.language)
+                .or(source.map(str::to_string)),
+        })
+    }
+
+    async fn detect(&self, text: &str) -> Result<Vec<Detection>, Error> {
+        let url = self.url()?;
+        let url = match url.strip_suffix("/translate") {
+            Some(base) => format!("{base}/detect"),
+            None => format!("{}/detect", url.trim_end_matches('/')),
+        };
+        let detections = self
+            .client
+            .post(url)
+            .json(&serde_json::json!({
+                "q": text,
+                "api_key": self.api_key,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<Detection>>()
+            .await?;
+        Ok(detections)
+    }
+}
+
+pub struct DeepL {
+    client: HttpClient,
+    url: String,
+    auth_key: String,
+}
+
+#[derive(Deserialize)]
+struct DeepLResponse {
+    translations: Vec<DeepLTranslation>,
+}
+
+#[derive(Deserialize)]
+struct DeepLTranslation {
+    detected_source_language: Option<String>,
+    text: String,
+}
+
+/// DeepL wants upper case codes and a regional variant for some target languages.
+fn deepl_target(lang: &str) -> String {
+    match lang {
+        "en" => "EN-US".to_string(),
+        "pt" => "PT-PT".to_string(),
+        _ => lang.to_uppercase(),
+    }
+}
+
+#[async_trait]
+impl Translator for DeepL {
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&str>,
+        target: &str,
+    ) -> Result<Translated, Error> {
+        let mut body = serde_json::json!({
+            "text": [text],
+            "target_lang": deepl_target(target),
+        });
+        if let Some(source) = source {
+            body["source_lang"] = source
+                .split('-')
+                .next()
+                .unwrap_or(source)
+                .to_uppercase()
+                .into();
+        }
+        let res = self
+            .client
+            .post(&self.url)
+            .header("Authorization", format!("DeepL-Auth-Key {}", self.auth_key))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<DeepLResponse>()
+            .await?;
+        let translation = res
+            .translations
+            .into_iter()
+            .next()
+            .ok_or("DeepL returned no translations")?;
+        Ok(Translated {
+            text: translation.text,
+            source: translation
+                .detected_source_language
+                .map(|l| l.to_lowercase()),
+        })
+    }
+}
+
+/// Any JSON API, described by a request body template and JSON pointers into the response.
+///
+/// `{text}`, `{source}` and `{target}` in the template are replaced by JSON strings, with
+/// `{source}` being `null` when the source language should be detected.
+pub struct JsonTemplate {
+    client: HttpClient,
+    url: String,
+    body: String,
+    headers: IndexMap<String, String>,
+    text_pointer: String,
+    source_pointer: Option<String>,
+}
+
+#[async_trait]
+impl Translator for JsonTemplate {
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&str>,
+        target: &str,
+    ) -> Result<Translated, Error> {
+        // Split around {text} first so placeholders inside the message are left alone
+        let source = serde_json::to_string(&source)?;
+        let target = serde_json::to_string(target)?;
+        let body = self
+            .body
+            .split("{text}")
+            .map(|part| {
+                part.replace("{source}", &source)
+                    .replace("{target}", &target)
+            })
+            .collect::<Vec<_>>()
+            .join(&serde_json::to_string(text)?);
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(body);
+        for (name, value) in &self.headers {
+            req = req.header(name, value);
+        }
+        let res = req
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+
+        let text = res
+            .pointer(&self.text_pointer)
+            .and_then(|t| t.as_str())
+            .ok_or_else(|| format!("No text at {} in the response", self.text_pointer))?;
+        let source = self
+            .source_pointer
+            .as_ref()
+            .and_then(|p| res.pointer(p))
+            .and_then(|s| s.as_str())
+            .map(str::to_string);
+        Ok(Translated {
+            text: text.to_string(),
+            source,
+        })
+    }
+}
+
+impl ReactionTranslateConfig {
+    pub fn allows_channel(&self, channel: ChannelId, category: Option<ChannelId>) -> bool {
+        let in_list = |channels: &[ChannelId], categories: &[ChannelId]| {
+            channels.contains(&channel) || category.is_some_and(|c| categories.contains(&c))
+        };
+        if in_list(&self.deny_channels, &self.deny_categories) {
+            return false;
+        }
+        (self.allow_channels.is_empty() && self.allow_categories.is_empty())
+            || in_list(&self.allow_channels, &self.allow_categories)
+    }
+
+    pub fn allows_member(&self, roles: &[RoleId]) -> bool {
+        self.required_role.is_none_or(|r| roles.contains(&r))
+    }
+}
+
+/// Line prefixes Discord renders as markdown, longest first.
+const LINE_MARKERS: &[&str] = &[">>> ", "> ", "-# ", "### ", "## ", "# ", "- ", "* "];
+
+/// Inline markdown markers that wrap text.
+const INLINE_MARKERS: &[&str] = &["**", "__", "~~", "||"];
+
+/// Text with everything a translator could mangle swapped for numbered placeholders.
+#[derive(Debug, PartialEq)]
+pub struct Protected {
+    pub text: String,
+    tokens: Vec<String>,
+}
+
+/// The length of a protected token starting at the beginning of `rest`, if there is one.
+fn token_len(rest: &str, line_start: bool) -> Option<usize> {
+    if let Some(code) = rest.strip_prefix("```") {
+        return Some(code.find("```").map_or(rest.len(), |end| end + 6));
+    }
+    if let Some(code) = rest.strip_prefix('`') {
+        return code.find('`').map(|end| end + 2);
+    }
+    if let Some(inner) = rest.strip_prefix('<') {
+        let end = inner.find('>')?;
+        let inner = &inner[..end];
+        let special = ["@", "#", ":", "a:", "t:", "/", "http"]
+            .iter()
+            .any(|p| inner.starts_with(p));
+        return (special && !inner.contains(char::is_whitespace)).then_some(end + 2);
+    }
+    if rest.starts_with("http://") || rest.starts_with("https://") {
+        return Some(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+    }
+    let markers = INLINE_MARKERS
+        .iter()
+        .chain(LINE_MARKERS.iter().filter(|_| line_start));
+    markers
+        .filter(|m| rest.starts_with(*m))
+        .map(|m| m.len())
+        .next()
+}
+
+pub fn protect(text: &str) -> Protected {
+    let mut protected = Protected {
+        text: String::new(),
+        tokens: vec![],
+    };
+    let mut i = 0;
+    while i < text.len() {
+        let line_start = i == 0 || text[..i].ends_with('\n');
+        match token_len(&text[i..], line_start) {
+            Some(len) => {
+                protected
+                    .text
+                    .push_str(&format!("⟦{}⟧", protected.tokens.len()));
+                protected.tokens.push(text[i..i + len].to_string());
+                i += len;
+            }
+            None => {
+                let c = text[i..].chars().next().unwrap();
+                protected.text.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    protected
+}
+
+impl Protected {
+    /// Whether there is anything left for a translator to work on.
+    pub fn has_text(&self) -> bool {
+        self.plain().chars().any(char::is_alphabetic)
+    }
+
+    /// The text without any of the protected tokens, for language detection.
+    pub fn plain(&self) -> String {
+        let mut plain = self.text.clone();
+        for n in 0..self.tokens.len() {
+            plain = plain.replace(&format!("⟦{n}⟧"), "");
+        }
+        plain
+    }
+
+    /// Puts the tokens back into `translated`, appending any the translator dropped.
+    pub fn restore(&self, translated: &str) -> String {
+        let mut restored = String::new();
+        let mut used = vec![false; self.tokens.len()];
+        let mut rest = translated;
+        while let Some(start) = rest.find('⟦') {
+            restored.push_str(&rest[..start]);
+            let after = &rest[start + '⟦'.len_utf8()..];
+            let token = after.find('⟧').and_then(|end| {
+                let n = after[..end].trim().parse::<usize>().ok()?;
+                Some((self.tokens.get(n)?, n, end))
+            });
+            match token {
+                Some((token, n, end)) => {
+                    restored.push_str(token);
+                    used[n] = true;
+                    rest = &after[end + '⟧'.len_utf8()..];
+                }
+                None => {
+                    restored.push('⟦');
+                    rest = after;
+                }
+            }
+        }
+        restored.push_str(rest);
+        for (token, _) in self.tokens.iter().zip(used).filter(|(_, used)| !used) {
+            restored.push(' ');
+            restored.push_str(token);
+        }
+        restored
+    }
+}
+
+/// Translates Discord flavoured text, keeping mentions, emoji, code, links and markdown intact.
+pub async fn translate_text(
+    translator: &dyn Translator,
+    text: &str,
+    source: Option<&str>,
+    target: &str,
+) -> Result<Translated, Error> {
+    let protected = protect(text);
+    if !protected.has_text() {
+        return Ok(Translated {
+            text: text.to_string(),
+            source: None,
+        });
+    }
+    let translated = translator
+        .translate(&protected.text, source, target)
+        .await?;
+    Ok(Translated {
+        text: protected.restore(&translated.text),
+        source: translated.source,
+    })
+}
+
+#[derive(Clone)]
+pub struct TranslatedMessage {
+    pub text: String,
+    pub embeds: Vec<Embed>,
+    pub source: Option<String>,
+}
+
+/// Marks where one part of a message ends in the text sent to the translator.
+const PART_SEPARATOR: &str = "⟦/⟧";
+
+/// Translates a message's content along with the text of its embeds, in a single backend
+/// request so it counts once against the rate limits.
+pub async fn translate_message(
+    translator: &dyn Translator,
+    content: &str,
+    embeds: &[Embed],
+    target: &str,
+) -> Result<TranslatedMessage, Error> {
+    let mut text = content.to_string();
+    let mut embeds = embeds.to_vec();
+    let mut parts = vec![&mut text];
+    for embed in &mut embeds {
+        parts.extend(embed.title.as_mut());
+        parts.extend(embed.description.as_mut());
+        parts.extend(embed.footer.as_mut().map(|f| &mut f.text));
+        for field in &mut embed.fields {
+            parts.push(&mut field.name);
+            parts.push(&mut field.value);
+        }
+    }
+
+    // Parts without any words, such as a bare link, are left as they are
+    let (parts, protected): (Vec<_>, Vec<_>) = parts
+        .into_iter()
+        .map(|part| {
+            let protected = protect(part);
+            (part, protected)
+        })
+        .filter(|(_, protected)| protected.has_text())
+        .unzip();
+    if parts.is_empty() {
+        return Ok(TranslatedMessage {
+            text,
+            embeds,
+            source: None,
+        });
+    }
+
+    let joined = protected
+        .iter()
+        .map(|p| p.text.as_str())
+        .collect::<Vec<_>>()
+        .join(&format!("\n{PART_SEPARATOR}\n"));
+    let translated = translator.translate(&joined, None, target).await?;
+    let pieces = translated.text.split(PART_SEPARATOR).collect::<Vec<_>>();
+    if pieces.len() != parts.len() {
+        return Err("The translation backend did not keep the message's parts apart".into());
+    }
+    for ((part, protected), piece) in parts.into_iter().zip(&protected).zip(pieces) {
+        *part = protected.restore(piece.trim_matches('\n'));
+    }
+
+    Ok(TranslatedMessage {
+        text,
+        embeds,
+        source: translated.source,
+    })
+}
+
+/// Splits `text` into messages within Discord's 2000 character limit, preferring line breaks.
+pub fn split_message(text: &str) -> Vec<String> {
+    let mut lines = vec![];
+    for line in text.split('\n') {
+        let mut line = line;
+        while line.len() >= 2000 {
+            let mut end = 1999;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            let cut = line[..end].rfind(' ').filter(|c| *c > 0).unwrap_or(end);
+            lines.push(line[..cut].to_string());
+            line = line[cut..].trim_start();
+        }
+        lines.push(line.to_string());
+    }
+    chunk_lines(lines)
+}
+
+/// Shortens `text` to fit in a single message.
+pub fn truncate_message(text: &str) -> String {
+    if text.len() < 2000 {
+        return text.to_string();
+    }
+    let mut end = 1996;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", &text[..end])
+}
+
+/// How many of `languages` were detected, most common first, as lines for a reply.
+pub fn render_language_stats(languages: &[String]) -> Vec<String> {
+    let mut counts: IndexMap<&str, usize> = IndexMap::new();
+    for language in languages {
+        *counts.entry(language).or_default() += 1;
+    }
+    counts.sort_by(|a_lang, a, b_lang, b| b.cmp(a).then(a_lang.cmp(b_lang)));
+    counts
+        .iter()
+        .map(|(language, count)| {
+            format!(
+                "{}: {count} ({}%)",
+                language_name(language),
+                count * 100 / languages.len()
+            )
+        })
+        .collect()
+}
+
+/// A least recently used cache, evicting the oldest entry once `capacity` is reached.
+pub struct Lru<K, V> {
+    capacity: usize,
+    entries: IndexMap<K, V>,
+}
+
+impl<K: Hash + Eq, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: IndexMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&mut V> {
+        let index = self.entries.get_index_of(key)?;
+        let last = self.entries.len() - 1;
+        self.entries.move_index(index, last);
+        self.entries.get_index_mut(last).map(|(_, v)| v)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.shift_remove(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.entries.shift_remove(&key);
+        if self.entries.len() >= self.capacity {
+            self.entries.shift_remove_index(0);
+        }
+        self.entries.insert(key, value);
+    }
+}
+
+/// Allows at most `limit` requests per key within any `window`.
+pub struct RateLimiter<K> {
+    limit: usize,
+    window: Duration,
+    requests: HashMap<K, VecDeque<Instant>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn allows(&mut self, key: &K, now: Instant) -> bool {
+        let Some(requests) = self.requests.get_mut(key) else {
+            return self.limit > 0;
+        };
+        while requests
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= self.window)
+        {
+            requests.pop_front();
+        }
+        requests.len() < self.limit
+    }
+
+    pub fn record(&mut self, key: K, now: Instant) {
+        self.requests.entry(key).or_default().push_back(now);
+    }
+}
+
+/// The reply holding every translation requested for one message.
+#[derive(Default)]
+pub struct TranslationReply {
+    /// Where the reply was posted, unset until it has been.
+    pub reply: Option<(ChannelId, MessageId)>,
+    /// When the original was last edited, so older translations can be dropped.
+    pub edited: Option<Timestamp>,
+    pub translations: IndexMap<String, TranslatedMessage>,
+    /// Who reacted for each language, so a translation can go once they all unreact.
+    requested_by: HashMap<String, Vec<UserId>>,
+}
+
+impl TranslationReply {
+    /// Whether `lang` is already shown for the message as of `edited`.
+    pub fn shows(&self, edited: Option<Timestamp>, lang: &str) -> bool {
+        self.edited == edited && self.translations.contains_key(lang)
+    }
+
+    /// Adds a translation of the message as of `edited`, dropping those of older versions.
+    pub fn add(
+        &mut self,
+        edited: Option<Timestamp>,
+        lang: String,
+        translated: TranslatedMessage,
+        by: Option<UserId>,
+    ) {
+        if self.edited != edited {
+            self.edited = edited;
+            self.translations.clear();
+            self.requested_by.clear();
+        }
+        self.request(&lang, by);
+        self.translations.insert(lang, translated);
+    }
+
+    pub fn request(&mut self, lang: &str, by: Option<UserId>) {
+        let users = self.requested_by.entry(lang.to_string()).or_default();
+        if let Some(by) = by
+            && !users.contains(&by)
+        {
+            users.push(by);
+        }
+    }
+
+    /// Forgets `user`'s request for `lang`, dropping the translation once nobody wants it.
+    /// Returns whether it was dropped.
+    pub fn withdraw(&mut self, lang: &str, user: UserId) -> bool {
+        let Some(users) = self.requested_by.get_mut(lang) else {
+            return false;
+        };
+        users.retain(|u| *u != user);
+        if !users.is_empty() {
+            return false;
+        }
+        self.requested_by.remove(lang);
+        self.translations.shift_remove(lang).is_some()
+    }
+
+    pub fn render(&self) -> String {
+        if let [(_, translated)] = self.translations.iter().collect::<Vec<_>>()[..] {
+            return truncate_message(&translated.text);
+        }
+        let text = self
+            .translations
+            .iter()
+            .filter(|(_, t)| !t.text.is_empty())
+            .map(|(lang, t)| format!("**{}:** {}", language_name(lang), t.text))
+            .collect::<Vec<_>>()
+            .join("\n");
+        truncate_message(&text)
+    }
+
+    /// The translated embeds of every language, up to Discord's limit of ten.
+    pub fn embeds(&self) -> Vec<Embed> {
+        self.translations
+            .values()
+            .flat_map(|t| t.embeds.iter().cloned())
+            .take(10)
+            .collect()
+    }
+}
+
+/// The backend language for a Discord locale such as `pt-BR`, falling back to English.
+pub fn locale_language(locale: &str) -> &'static str {
+    match locale {
+        "zh-CN" => "zh-Hans",
+        "zh-TW" => "zh-Hant",
+        "no" => "nb",
+        _ => {
+            let prefix = locale.split('-').next().unwrap_or(locale);
+            LANGUAGES
+                .iter()
+                .find(|(c, _)| *c == prefix)
+                .map_or("en", |(c, _)| c)
+        }
+    }
+}
+
+/// The language codes whose code or name start with `partial`, for autocomplete.
+pub fn matching_languages(partial: &str) -> impl Iterator<Item = (&'static str, &'static str)> {
+    let partial = partial.to_lowercase();
+    LANGUAGES.iter().copied().filter(move |(code, name)| {
+        code.to_lowercase().starts_with(&partial) || name.to_lowercase().starts_with(&partial)
+    })
+}
+
+/// Whether `detected` is `language`, ignoring script and regional variants.
+pub fn same_language(detected: &str, language: &str) -> bool {
+    let base = |l: &str| l.split('-').next().unwrap_or(l).to_lowercase();
+    base(detected) == base(language)
+}
+
+pub fn language_name(code: &str) -> &str {
+    LANGUAGES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or(code, |(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Upper cases everything, counting the requests it gets.
+    #[derive(Default)]
+    struct Shouting {
+        requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Translator for Shouting {
+        async fn translate(
+            &self,
+            text: &str,
+            _source: Option<&str>,
+            _target: &str,
+        ) -> Result<Translated, Error> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            Ok(Translated {
+                text: text.to_uppercase(),
+                source: Some("sv".to_string()),
+            })
+        }
+    }
+
+    #[test]
+    fn flag_test() {
+        assert_eq!(flag_country("🇺🇸").as_deref(), Some("US"));
+        assert_eq!(flag_country("🇺"), None);
+        assert_eq!(flag_country("🇺🇸🇺"), None);
+        assert_eq!(flag_country("👍"), None);
+
+        let overrides = IndexMap::from([("CA".to_string(), "fr".to_string())]);
+        assert_eq!(flag_language("US", &overrides).as_deref(), Some("en"));
+        assert_eq!(flag_language("BR", &overrides).as_deref(), Some("pt"));
+        assert_eq!(flag_language("MX", &overrides).as_deref(), Some("es"));
+        assert_eq!(flag_language("CN", &overrides).as_deref(), Some("zh-Hans"));
+        assert_eq!(flag_language("TW", &overrides).as_deref(), Some("zh-Hant"));
+        assert_eq!(flag_language("CA", &overrides).as_deref(), Some("fr"));
+        assert_eq!(flag_language("AQ", &overrides), None);
+    }
+
+    #[test]
+    fn flag_table_test() {
+        for (country, language) in FLAG_LANGUAGES {
+            assert!(
+                LANGUAGES.iter().any(|(code, _)| code == language),
+                "{country} maps to unknown language {language}"
+            );
+            assert_eq!(
+                FLAG_LANGUAGES.iter().filter(|(c, _)| c == country).count(),
+                1,
+                "{country} is mapped twice"
+            );
+        }
+    }
+
+    #[test]
+    fn locale_test() {
+        assert_eq!(locale_language("pt-BR"), "pt");
+        assert_eq!(locale_language("sv-SE"), "sv");
+        assert_eq!(locale_language("zh-TW"), "zh-Hant");
+        assert_eq!(locale_language("no"), "nb");
+        assert_eq!(locale_language("hr"), "en");
+
+        assert!(same_language("zh", "zh-Hans"));
+        assert!(same_language("EN", "en"));
+        assert!(!same_language("sv", "en"));
+
+        let matches = matching_languages("sw").collect::<Vec<_>>();
+        assert_eq!(matches, vec![("sv", "Swedish")]);
+        assert_eq!(matching_languages("ZH").count(), 2);
+    }
+
+    #[test]
+    fn protect_test() {
+        let text = "**Hej** <@123>, se `kod` och https://example.com <:wave:456>\n> citat";
+        let protected = protect(text);
+        assert_eq!(
+            protected.text,
+            "⟦0⟧Hej⟦1⟧ ⟦2⟧, se ⟦3⟧ och ⟦4⟧ ⟦5⟧\n⟦6⟧citat"
+        );
+        assert!(protected.has_text());
+        assert_eq!(
+            protected.restore("⟦0⟧Hi⟦1⟧ ⟦2⟧, see ⟦ 3 ⟧ and ⟦4⟧ ⟦5⟧\n⟦6⟧quote"),
+            "**Hi** <@123>, see `kod` and https://example.com <:wave:456>\n> quote"
+        );
+        assert_eq!(
+            protected.restore("⟦0⟧Hi⟦1⟧ ⟦2⟧ ⟦3⟧ ⟦4⟧ ⟦6⟧quote"),
+            "**Hi** <@123> `kod` https://example.com > quote <:wave:456>"
+        );
+
+        assert!(!protect("<@1> https://example.com ```\nlet x = 1;\n```").has_text());
+        assert_eq!(protect("a < b > c").text, "a < b > c");
+    }
+
+    #[test]
+    fn split_test() {
+        let long = "word ".repeat(1000);
+        let chunks = split_message(&format!("short\n{long}"));
+        assert!(chunks.iter().all(|c| c.len() <= 2000));
+        assert_eq!(chunks[0].lines().next(), Some("short"));
+        assert_eq!(chunks.concat().matches("word").count(), 1000);
+
+        assert_eq!(split_message("hi"), vec!["hi"]);
+        assert_eq!(truncate_message("hi"), "hi");
+        assert!(truncate_message(&"é".repeat(1500)).ends_with('…'));
+    }
+
+    #[test]
+    fn reaction_config_test() {
+        let id = ChannelId::new;
+        let config = ReactionTranslateConfig {
+            allow_categories: vec![id(10)],
+            deny_channels: vec![id(2)],
+            required_role: Some(RoleId::new(5)),
+            ..Default::default()
+        };
+        assert!(config.allows_channel(id(1), Some(id(10))));
+        assert!(!config.allows_channel(id(2), Some(id(10))));
+        assert!(!config.allows_channel(id(3), Some(id(11))));
+        assert!(!config.allows_channel(id(3), None));
+        assert!(config.allows_member(&[RoleId::new(4), RoleId::new(5)]));
+        assert!(!config.allows_member(&[RoleId::new(4)]));
+
+        let open = ReactionTranslateConfig {
+            deny_categories: vec![id(11)],
+            ..Default::default()
+        };
+        assert!(open.allows_channel(id(3), None));
+        assert!(!open.allows_channel(id(3), Some(id(11))));
+        assert!(open.allows_member(&[]));
+    }
+
+    #[test]
+    fn withdraw_test() {
+        let translated = |text: &str| TranslatedMessage {
+            text: text.to_string(),
+            embeds: vec![],
+            source: None,
+        };
+        let (a, b) = (UserId::new(1), UserId::new(2));
+        let mut reply = TranslationReply::default();
+        reply.add(None, "en".to_string(), translated("Hello"), Some(a));
+        reply.request("en", Some(b));
+        reply.add(None, "de".to_string(), translated("Hallo"), Some(a));
+        assert!(reply.shows(None, "en"));
+        assert_eq!(reply.render(), "**English:** Hello\n**German:** Hallo");
+
+        assert!(!reply.withdraw("en", a));
+        assert!(reply.withdraw("de", a));
+        assert!(!reply.withdraw("fr", a));
+        assert_eq!(reply.render(), "Hello");
+        assert!(reply.withdraw("en", b));
+        assert!(reply.translations.is_empty());
+
+        reply.add(None, "en".to_string(), translated("Hello"), Some(a));
+        let edited = Some(Timestamp::now());
+        assert!(!reply.shows(edited, "en"));
+        reply.add(edited, "de".to_string(), translated("Hallo"), Some(b));
+        assert!(!reply.withdraw("en", a));
+        assert_eq!(reply.render(), "Hallo");
+    }
+
+    #[test]
+    fn language_stats_test() {
+        let languages = ["sv", "en", "sv", "de", "sv", "en"].map(String::from);
+        assert_eq!(
+            render_language_stats(&languages),
+            vec!["Swedish: 3 (50%)", "English: 2 (33%)", "German: 1 (16%)"]
+        );
+        assert!(render_language_stats(&[]).is_empty());
+    }
+
+    #[test]
+    fn lru_test() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        assert_eq!(lru.get(&"a"), Some(&mut 1));
+
+        lru.insert("c", 3);
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"a"), Some(&mut 1));
+        assert_eq!(lru.get(&"c"), Some(&mut 3));
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        for _ in 0..2 {
+            assert!(limiter.allows(&1, start));
+            limiter.record(1, start);
+        }
+        assert!(!limiter.allows(&1, start + Duration::from_secs(59)));
+        assert!(limiter.allows(&2, start));
+        assert!(limiter.allows(&1, start + Duration::from_secs(60)));
+    }
+
+    /// Serves `response` to a single request and hands back the raw request it received.
+    async fn mock_server(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/translate", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 4096];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(str::to_string)
+                        })
+                        .map_or(0, |l| l.parse().unwrap());
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn translate_message_test() {
+        let embed: Embed = serde_json::from_value(serde_json::json!({
+            "title": "Rubrik",
+            "description": "<https://example.com>",
+            "fields": [{"name": "Namn", "value": "Hej <@1>", "inline": false}],
+        }))
+        .unwrap();
+        let translator = Shouting::default();
+        let translated = translate_message(&translator, "Hej **du**", &[embed], "en")
+            .await
+            .unwrap();
+
+        assert_eq!(translator.requests.load(Ordering::SeqCst), 1);
+        assert_eq!(translated.text, "HEJ **DU**");
+        assert_eq!(translated.source.as_deref(), Some("sv"));
+        let embed = &translated.embeds[0];
+        assert_eq!(embed.title.as_deref(), Some("RUBRIK"));
+        assert_eq!(embed.description.as_deref(), Some("<https://example.com>"));
+        assert_eq!(embed.fields[0].name, "NAMN");
+        assert_eq!(embed.fields[0].value, "HEJ <@1>");
+    }
+
+    #[tokio::test]
+    async fn libretranslate_test() {
+        let (url, request) = mock_server(
+            r#"{"translatedText": "Hello", "detectedLanguage": {"confidence": 90, "language": "sv"}}"#,
+        )
+        .await;
+        let translator = build_translator(
+            &BackendConfig::LibreTranslate {
+                url: Some(url),
+                api_key: None,
+            },
+            HttpClient::new(),
+        );
+
+        assert_eq!(
+            translator.translate("Hej", None, "en").await.unwrap(),
+            Translated {
+                text: "Hello".to_string(),
+                source: Some("sv".to_string()),
+            }
+        );
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /translate"));
+        assert!(request.contains(r#""q":"Hej""#) && request.contains(r#""source":"auto""#));
+    }
+
+    #[tokio::test]
+    async fn detect_test() {
+        let (url, request) = mock_server(r#"[{"confidence": 92.5, "language": "sv"}]"#).await;
+        let translator = build_translator(
+            &BackendConfig::LibreTranslate {
+                url: Some(url),
+                api_key: None,
+            },
+            HttpClient::new(),
+        );
+        assert_eq!(
+            translator.detect("Hej").await.unwrap(),
+            vec![Detection {
+                language: "sv".to_string(),
+                confidence: Some(92.5),
+            }]
+        );
+        assert!(request.await.unwrap().starts_with("POST /detect"));
+
+        let (url, _) = mock_server(
+            r#"{"translations": [{"detected_source_language": "SV", "text": "Hello"}]}"#,
+        )
+        .await;
+        let translator = build_translator(
+            &BackendConfig::DeepL {
+                url,
+                auth_key: "secret".to_string(),
+            },
+            HttpClient::new(),
+        );
+        assert_eq!(
+            translator.detect("Hej").await.unwrap(),
+            vec![Detection {
+                language: "sv".to_string(),
+                confidence: None,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn deepl_test() {
+        let (url, request) = mock_server(
+            r#"{"translations": [{"detected_source_language": "SV", "text": "Hello"}]}"#,
+        )
+        .await;
+        let translator = build_translator(
+            &BackendConfig::DeepL {
+                url,
+                auth_key: "secret".to_string(),
+            },
+            HttpClient::new(),
+        );
+
+        assert_eq!(
+            translator.translate("Hej", None, "en").await.unwrap(),
+            Translated {
+                text: "Hello".to_string(),
+                source: Some("sv".to_string()),
+            }
+        );
+        let request = request.await.unwrap().to_lowercase();
+        assert!(request.contains("authorization: deepl-auth-key secret"));
+        assert!(request.contains(r#""target_lang":"en-us""#));
+    }
+
+    #[tokio::test]
+    async fn template_test() {
+        let (url, request) = mock_server(r#"{"data": {"out": "Hello", "from": "sv"}}"#).await;
+        let translator = build_translator(
+            &BackendConfig::Template {
+                url,
+                body: r#"{"input": {text}, "from": {source}, "to": {target}}"#.to_string(),
+                headers: IndexMap::from([("X-Key".to_string(), "k".to_string())]),
+                text_pointer: "/data/out".to_string(),
+                source_pointer: Some("/data/from".to_string()),
+            },
+            HttpClient::new(),
+        );
+
+        assert_eq!(
+            translator
+                .translate("Hej \"där\"", None, "en")
+                .await
+                .unwrap(),
+            Translated {
+                text: "Hello".to_string(),
+                source: Some("sv".to_string()),
+            }
+        );
+        let request = request.await.unwrap();
+        assert!(request.contains(r#"{"input": "Hej \"där\"", "from": null, "to": "en"}"#));
+        assert!(request.to_lowercase().contains("x-key: k"));
+
+        let (url, request) = mock_server(r#"{"data": {"out": "Hello"}}"#).await;
+        let translator = build_translator(
+            &BackendConfig::Template {
+                url,
+                body: r#"{"input": {text}, "from": {source}, "to": {target}}"#.to_string(),
+                headers: IndexMap::new(),
+                text_pointer: "/data/out".to_string(),
+                source_pointer: None,
+            },
+            HttpClient::new(),
+        );
+        translator
+            .translate("Hej {target} {source}", Some("sv"), "en")
+            .await
+            .unwrap();
+        assert!(
+            request
+                .await
+                .unwrap()
+                .contains(r#"{"input": "Hej {target} {source}", "from": "sv", "to": "en"}"#)
+        );
+    }
+}